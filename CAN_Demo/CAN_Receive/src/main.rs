@@ -13,10 +13,6 @@ use embedded_hal::{
 
 
 // CAN BUS --------------------------------------------------------------------
-extern crate alloc;
-
-use embedded_can::nb::Can;
-use embedded_can::{Frame, StandardId};
 // use embedded_hal::digital::StatefulOutputPin;
 // use panic_probe as _;
 // use panic_halt as _;
@@ -27,12 +23,10 @@ use rp2040_hal::{entry, pac, pac::interrupt, Sio, Watchdog};
 use rp_pico::XOSC_CRYSTAL_FREQ;
 // const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
 
-use can2040::global_allocator::init_allocator;
-use can2040::CanFrame;
+use can2040::Can2040;
+use tgis::AnyMessage;
 
 const CONFIG_CANBUS_FREQUENCY: u32 = 10_000;
-const CONFIG_RP2040_CANBUS_GPIO_RX: u32 = 8;
-const CONFIG_RP2040_CANBUS_GPIO_TX: u32 = 7;
 // ----------------------------------------------------------------------------
 
 // USB Device support
@@ -61,8 +55,6 @@ fn panic(panic_info: &PanicInfo) -> ! {
 
 #[entry]
 fn main() -> ! {
-    init_allocator();
-
     // Grab the singleton objects
     let mut pac = pac::Peripherals::take().unwrap();
     let mut core = pac::CorePeripherals::take().unwrap();
@@ -109,12 +101,18 @@ fn main() -> ! {
 
 
 
-    let mut can_bus = can2040::initialize_cbus(
-        &mut core,
+    // GPIO 8 <=> CAN RX, GPIO 7 <=> CAN TX
+    let mut can_bus = Can2040::new(
+        pac.PIO0,
+        pins.gpio8,
+        pins.gpio7,
         CONFIG_CANBUS_FREQUENCY,
-        CONFIG_RP2040_CANBUS_GPIO_RX,
-        CONFIG_RP2040_CANBUS_GPIO_TX,
+        &clocks.system_clock,
+        &mut pac.RESETS,
+        &mut core.NVIC,
     );
+    // Report our own transmissions too, as candump does
+    can_bus.set_loopback(true);
 
 
     let mut count = 0u64;
@@ -129,12 +127,25 @@ fn main() -> ! {
         if n % 1_000_000 == 13 {
             // write!(usb, "starting loop number {:?}\r\n", n).unwrap();
             led_pin.set_low().unwrap();
+            // The host asked for another bitrate
+            if let Some(bitrate) = usb.take_bitrate_request() {
+                can_bus.restart(bitrate);
+                write!(usb, "CAN bitrate set to {}\r\n", bitrate);
+            }
             // Await CAN packet
-            match can_bus.receive() {
-                Ok(f) => {
-                    // info!("Received packet: {:?}", f.data()[3]);
-                    write!(usb, "Received data: {:?}\r\n", f.data()[3]);
+            match can_bus.receive_timestamped() {
+                Ok(sent) if sent.echo => {
+                    write!(usb, "Sent frame: {}\r\n", sent.frame);
                 }
+                Ok(received) => match AnyMessage::from_frame(&received.frame) {
+                    Ok((node, message)) => {
+                        write!(usb, "Received from node {}: {:?}\r\n", node.as_raw(), message);
+                    }
+                    // Not a TGIS message, or a malformed one
+                    Err(_) => {
+                        write!(usb, "Received frame: {}\r\n", received.frame);
+                    }
+                },
                 // Err(nb::Error::Other(err)) => {
                 //     // error!("Errors in reading CAN frame, {:?}", err);
                 // }
@@ -150,11 +161,3 @@ fn main() -> ! {
     }
 
 }
-
-pub fn create_frame(cob_id: u16, data: u64) -> CanFrame {
-    CanFrame::new(
-        StandardId::new(cob_id).expect("error in create standard id"),
-        &[1, 2, 3, (data & 0xff) as u8],
-    )
-    .expect("error in create_frame")
-}
\ No newline at end of file
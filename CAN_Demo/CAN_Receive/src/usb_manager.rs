@@ -6,9 +6,14 @@ use usb_device::{
 };
 use usbd_serial::SerialPort;
 
+/// Bitrates selected by the slcan `S0`..`S8` commands.
+const SLCAN_BITRATES: [u32; 9] =
+    [10_000, 20_000, 50_000, 100_000, 125_000, 250_000, 500_000, 800_000, 1_000_000];
+
 pub struct UsbManager {
     device: UsbDevice<'static, hal::usb::UsbBus>,
     serial: SerialPort<'static, hal::usb::UsbBus>,
+    bitrate_request: Option<u32>,
 }
 
 impl UsbManager {
@@ -23,7 +28,7 @@ impl UsbManager {
             .device_class(usbd_serial::USB_CLASS_CDC)
             .build();
 
-        UsbManager { device, serial }
+        UsbManager { device, serial, bitrate_request: None }
     }
 
     pub unsafe fn interrupt(&mut self) {
@@ -33,12 +38,24 @@ impl UsbManager {
                 Err(_e) => {
                     // Do nothing
                 }
-                Ok(_count) => {
-                    // Do nothing
+                Ok(count) => {
+                    // slcan style bitrate command, e.g. "S4\r" for 125 kbit/s
+                    for cmd in buf[..count].windows(2) {
+                        if cmd[0] == b'S' && cmd[1].is_ascii_digit() {
+                            if let Some(&bitrate) = SLCAN_BITRATES.get((cmd[1] - b'0') as usize) {
+                                self.bitrate_request = Some(bitrate);
+                            }
+                        }
+                    }
                 }
             }
         }
     }
+
+    /// Returns the bitrate last requested by the host, if any since the last call.
+    pub fn take_bitrate_request(&mut self) -> Option<u32> {
+        critical_section::with(|_| self.bitrate_request.take())
+    }
 }
 
 impl core::fmt::Write for UsbManager {
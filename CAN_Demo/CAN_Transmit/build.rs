@@ -1,5 +1,3 @@
-extern crate bindgen;
-
 use std::env;
 use std::path::PathBuf;
 
@@ -9,6 +7,34 @@ fn main() {
     println!("dir = {}", dir);
 
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    #[cfg(feature = "rp2040")]
+    build_can2040(out);
+
+    // Depth of the queues, fixed at compile time since nothing is allocated.
+    let rx_queue_len = queue_len("CAN2040_RX_QUEUE_LEN", 32);
+    assert!(rx_queue_len.is_power_of_two(), "CAN2040_RX_QUEUE_LEN must be a power of two");
+    let tx_queue_len = queue_len("CAN2040_TX_QUEUE_LEN", 16);
+    std::fs::write(
+        out.join("config.rs"),
+        format!(
+            "pub const RX_QUEUE_LEN: usize = {};\npub const TX_QUEUE_LEN: usize = {};\n",
+            rx_queue_len, tx_queue_len
+        ),
+    )
+    .expect("Failed to write config.rs");
+}
+
+fn queue_len(var: &str, default: usize) -> usize {
+    println!("cargo:rerun-if-env-changed={}", var);
+    match env::var(var) {
+        Ok(len) => len.parse().unwrap_or_else(|_| panic!("{} must be a number", var)),
+        Err(_) => default,
+    }
+}
+
+/// Generates the bindings for `c_lib/can2040.h` and links the prebuilt library.
+#[cfg(feature = "rp2040")]
+fn build_can2040(out: &std::path::Path) {
     let header_path = PathBuf::from("c_lib/can2040.h");
     let bindings = bindgen::Builder::default()
         .clang_arg("--target=thumbv6m-none-eabi")
@@ -21,6 +47,7 @@ fn main() {
     let bindings_path = out.join("can2040_lib.rs");
     bindings.write_to_file(&bindings_path).expect("Failed to write bindings to file");
     println!("cargo:rerun-if-changed={}", bindings_path.display());
+
     let lib_path = env::current_dir().unwrap().join("c_lib");
     println!("cargo:rustc-link-search=native={}", lib_path.display());
     println!("cargo:rustc-link-lib=static=can2040");
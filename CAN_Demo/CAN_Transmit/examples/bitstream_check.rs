@@ -0,0 +1,79 @@
+//! Checks `can2040::bitstream` against the prebuilt can2040 library
+//!
+//! Encodes pseudo-random frames with both `can2040::bitstream` and the C
+//! library's `can2040_transmit`, and reports any difference in the CRC or the
+//! stuffed words over defmt. No CAN hardware is needed.
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use defmt_rtt as _;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+use panic_halt as _;
+use rp2040_hal::entry;
+
+use can2040::{bitstream, can2040_reference, CanFrame};
+
+const FRAMES: u32 = 100_000;
+
+// Second-stage bootloader ------------------------------------------------------------------------
+#[link_section = ".boot2"]
+#[no_mangle]
+#[used]
+pub static BOOT2_FIRMWARE: [u8; 256] = rp2040_boot2::BOOT_LOADER_GD25Q64CS;
+
+#[entry]
+fn main() -> ! {
+    info!("Comparing {} frames", FRAMES);
+    let mut rng = 0x2545_F491u32;
+    let mut mismatches = 0u32;
+    for n in 0..FRAMES {
+        let frame = random_frame(&mut rng);
+        let (crc, words, used) = can2040_reference(&frame);
+        let (ref_words, ref_used) = bitstream::can2040_words(&frame);
+        if crc != bitstream::crc15(&frame) || used != ref_used || words[..used] != ref_words[..used]
+        {
+            mismatches += 1;
+            error!("#{}: {}", n, frame);
+            error!("  can2040:   crc {:04x} words {:08x}", crc, words[..used]);
+            error!(
+                "  bitstream: crc {:04x} words {:08x}",
+                bitstream::crc15(&frame),
+                ref_words[..ref_used]
+            );
+        }
+    }
+    info!("Done, {} mismatches", mismatches);
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+fn xorshift(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+/// Mostly short IDs and runs of equal bits, which exercise the stuffing.
+fn random_frame(rng: &mut u32) -> CanFrame {
+    let r = xorshift(rng);
+    let id: Id = match r & 1 {
+        0 => StandardId::new((xorshift(rng) & 0x7FF) as u16).unwrap().into(),
+        _ => ExtendedId::new(xorshift(rng) & 0x1FFF_FFFF).unwrap().into(),
+    };
+    let len = (r >> 1) as usize % 9;
+    if r & 0x400 != 0 {
+        return CanFrame::new_remote(id, len).unwrap();
+    }
+    let mut data = [0u8; 8];
+    for byte in &mut data[..len] {
+        *byte = match xorshift(rng) % 4 {
+            0 => 0x00,
+            1 => 0xFF,
+            _ => xorshift(rng) as u8,
+        };
+    }
+    CanFrame::new(id, &data[..len]).unwrap()
+}
@@ -4,28 +4,24 @@
 #![no_std]
 #![no_main]
 
-extern crate alloc;
-
 use defmt::*;
 use defmt_rtt as _;
 use embedded_can::nb::Can;
-use embedded_can::{Frame, StandardId};
+use embedded_can::Frame;
 use embedded_hal::digital::InputPin;
 use embedded_hal::digital::StatefulOutputPin;
 // use panic_probe as _;
 use panic_halt as _;
 use rp2040_hal::clocks::init_clocks_and_plls;
 use rp2040_hal::gpio::Pins;
-use rp2040_hal::{entry, pac, Sio, Watchdog};
+use rp2040_hal::{entry, pac, Sio, Timer, Watchdog};
 use rp_pico::XOSC_CRYSTAL_FREQ;
 // const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
 
-use can2040::global_allocator::init_allocator;
-use can2040::{Can2040, CanFrame};
+use can2040::{Can2040, Scheduler};
+use tgis::{AnyMessage, Leak, Message, NodeId};
 
 const CONFIG_CANBUS_FREQUENCY: u32 = 10_000;
-const CONFIG_RP2040_CANBUS_GPIO_RX: u32 = 8;
-const CONFIG_RP2040_CANBUS_GPIO_TX: u32 = 7;
 
 // Second-stage bootloader ------------------------------------------------------------------------
 #[link_section = ".boot2"]
@@ -35,14 +31,13 @@ pub static BOOT2_FIRMWARE: [u8; 256] = rp2040_boot2::BOOT_LOADER_GD25Q64CS;
 
 #[entry]
 fn main() -> ! {
-    init_allocator();
     info!("Program start");
     let mut pac = pac::Peripherals::take().unwrap();
     let mut core = pac::CorePeripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
     let sio = Sio::new(pac.SIO);
 
-    let _clocks = init_clocks_and_plls(
+    let clocks = init_clocks_and_plls(
         XOSC_CRYSTAL_FREQ,
         pac.XOSC,
         pac.CLOCKS,
@@ -59,37 +54,51 @@ fn main() -> ! {
     let mut led_pin = pins.gpio13.into_push_pull_output();
     let mut leak_pin = pins.gpio24.into_pull_up_input();
 
-    let mut can_bus = can2040::initialize_cbus(
-        &mut core,
+    // GPIO 8 <=> CAN RX, GPIO 7 <=> CAN TX
+    let mut can_bus = Can2040::new(
+        pac.PIO0,
+        pins.gpio8,
+        pins.gpio7,
         CONFIG_CANBUS_FREQUENCY,
-        CONFIG_RP2040_CANBUS_GPIO_RX,
-        CONFIG_RP2040_CANBUS_GPIO_TX,
+        &clocks.system_clock,
+        &mut pac.RESETS,
+        &mut core.NVIC,
     );
 
-    let mut count = 0u64;
-    // let mut packet_num = 0u64;
-    loop {
-        count += 1;
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
 
-        if count % 1_000_000 == 13 {
-            // packet_num += 1;
-            let leak = leak_pin.is_low().unwrap() as u64;
-            info!("leak: {:?}", leak);
+    // Broadcast the leak state twice a second.
+    let mut leak_frame = || {
+        let leak = Leak { detected: leak_pin.is_low().unwrap() };
+        info!("leak: {}", leak);
+        led_pin.toggle().unwrap();
+        Some(leak.to_frame(NodeId::STATUS))
+    };
+    let mut scheduler = Scheduler::<1>::new();
+    let leak_slot = scheduler.add_producer(&mut leak_frame, 500_000, 0).unwrap();
 
-            let f = create_frame(5, leak);
-            match <Can2040 as embedded_can::blocking::Can>::transmit(&mut can_bus, &f) {
-                Ok(_) => {}
-                Err(err) => {
-                    error!("Transmit error: {}", err);
-                }
+    loop {
+        match scheduler.poll(&mut can_bus, timer.get_counter().ticks()) {
+            Ok(0) => {}
+            Ok(_) => {
+                info!("CAN status: {}", can_bus.status());
+                info!("CAN stats: {}", can_bus.stats());
+                info!("Leak message timing: {}", scheduler.stats(leak_slot));
+            }
+            Err(err) => {
+                error!("Transmit error: {}", err);
             }
-            info!("Transmitted package: {:?}", f);
-            led_pin.toggle().unwrap();
         }
         match can_bus.receive() {
-            Ok(f) => {
-                info!("Received packet: {:?}", f);
+            Ok(f) if f.is_remote_frame() && f.id() == Leak::id(NodeId::STATUS).into() => {
+                // Someone asked for our leak state instead of waiting for the next broadcast.
+                scheduler.trigger(leak_slot);
+                info!("Answering remote request");
             }
+            Ok(f) => match AnyMessage::from_frame(&f) {
+                Ok((node, message)) => info!("Received {} from node {}", message, node.as_raw()),
+                Err(_) => info!("Received packet: {:?}", f),
+            },
             Err(nb::Error::Other(err)) => {
                 error!("Errors in reading CAN frame, {:?}", err);
             }
@@ -97,11 +106,3 @@ fn main() -> ! {
         }
     }
 }
-
-pub fn create_frame(cob_id: u16, data: u64) -> CanFrame {
-    CanFrame::new(
-        StandardId::new(cob_id).expect("error in create standard id"),
-        &[1, 2, 3, (data & 0xff) as u8],
-    )
-    .expect("error in create_frame")
-}
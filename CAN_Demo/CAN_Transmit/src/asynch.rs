@@ -0,0 +1,101 @@
+//! Async receive/transmit for executors such as embassy or RTIC 2.
+//!
+//! The futures register a waker with the bus and return `Pending`; the can2040
+//! callback wakes them from the PIO interrupt when a frame arrives, a transmit
+//! completes (freeing a slot in the C library's queue) or an error is raised.
+
+#[cfg(any(feature = "rp2040", feature = "mock"))]
+use core::{cell::RefCell, task::Poll, task::Waker};
+
+#[cfg(any(feature = "rp2040", feature = "mock"))]
+use critical_section::Mutex;
+
+#[cfg(feature = "rp2040")]
+use crate::core::{Can2040, Instance};
+#[cfg(feature = "rp2040")]
+use crate::frame::{CanError, CanFrame};
+
+/// Holds the waker of the task waiting on one side (RX or TX) of a bus.
+#[cfg(any(feature = "rp2040", feature = "mock"))]
+pub(crate) struct WakerSlot(Mutex<RefCell<Option<Waker>>>);
+
+#[cfg(any(feature = "rp2040", feature = "mock"))]
+impl WakerSlot {
+    pub(crate) const fn new() -> Self {
+        Self(Mutex::new(RefCell::new(None)))
+    }
+
+    pub(crate) fn register(&self, waker: &Waker) {
+        critical_section::with(|cs| {
+            let mut slot = self.0.borrow(cs).borrow_mut();
+            match slot.as_ref() {
+                Some(w) if w.will_wake(waker) => {}
+                _ => *slot = Some(waker.clone()),
+            }
+        })
+    }
+
+    pub(crate) fn wake(&self) {
+        if let Some(waker) = critical_section::with(|cs| self.0.borrow(cs).borrow_mut().take()) {
+            waker.wake();
+        }
+    }
+}
+
+/// Retries the non-blocking `op` until it stops returning `WouldBlock`,
+/// parking the task on `slot` in between.
+#[cfg(any(feature = "rp2040", feature = "mock"))]
+pub(crate) async fn poll_nb<T, E>(
+    slot: &WakerSlot,
+    mut op: impl FnMut() -> nb::Result<T, E>,
+) -> Result<T, E> {
+    fn ready<T, E>(result: nb::Result<T, E>) -> Poll<Result<T, E>> {
+        match result {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(nb::Error::Other(err)) => Poll::Ready(Err(err)),
+            Err(nb::Error::WouldBlock) => Poll::Pending,
+        }
+    }
+
+    core::future::poll_fn(|cx| {
+        if let Poll::Ready(result) = ready(op()) {
+            return Poll::Ready(result);
+        }
+        slot.register(cx.waker());
+        // The event may have happened before the waker was in place.
+        ready(op())
+    })
+    .await
+}
+
+/// Async counterpart of `embedded_can::nb::Can`.
+///
+/// The method names match the `nb` and `blocking` traits, so import only one
+/// of them into a scope that calls `receive`/`transmit`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncCan {
+    type Frame: embedded_can::Frame;
+    type Error: embedded_can::Error;
+
+    /// Waits until the frame has been handed to the bus for transmission.
+    async fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error>;
+
+    /// Waits for the next received frame.
+    async fn receive(&mut self) -> Result<Self::Frame, Self::Error>;
+}
+
+#[cfg(feature = "rp2040")]
+impl<P: Instance> AsyncCan for Can2040<P> {
+    type Frame = CanFrame;
+    type Error = CanError;
+
+    async fn transmit(&mut self, frame: &CanFrame) -> Result<(), CanError> {
+        let slot = self.tx_waker();
+        poll_nb(slot, || self.try_transmit(frame)).await
+    }
+
+    async fn receive(&mut self) -> Result<CanFrame, CanError> {
+        let slot = self.rx_waker();
+        poll_nb(slot, || embedded_can::nb::Can::receive(self)).await
+    }
+}
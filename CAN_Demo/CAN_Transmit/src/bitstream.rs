@@ -0,0 +1,462 @@
+//! Encoder and decoder for the CAN 2.0 bitstream.
+//!
+//! This is an independent implementation of what can2040 puts on and reads
+//! from the wire: bit stuffing, CRC-15 and the fixed-form fields of data and
+//! remote frames. It runs on the host, e.g. to decode logic analyser captures
+//! with [`Sampler`] and [`Decoder`]. The prebuilt C library only runs on the
+//! RP2040, so the host tests compare it with words captured from can2040, and
+//! `examples/bitstream_check.rs` compares it with can2040 on the board.
+//!
+//! Bits are `true` for recessive (1) and `false` for dominant (0).
+
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+
+use crate::frame::CanFrame;
+
+/// Upper bound on the bits of one frame from SOF to the end of EOF,
+/// including stuff bits.
+pub const MAX_FRAME_BITS: usize = 160;
+
+const WORDS: usize = MAX_FRAME_BITS / 32;
+const CRC15_POLY: u16 = 0x4599;
+
+/// Recessive bits after the CRC delimiter: ACK slot (as sent by the
+/// transmitter), ACK delimiter and EOF.
+const TRAILER_BITS: usize = 9;
+/// Recessive bits before a SOF that are taken as an idle bus: ACK delimiter,
+/// EOF and intermission.
+const IDLE_BITS: u32 = 11;
+
+/// A sequence of up to [`MAX_FRAME_BITS`] bits, packed MSB first like the
+/// `stuffed_data` words of can2040.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Bits {
+    words: [u32; WORDS],
+    len: usize,
+}
+
+impl Bits {
+    pub const fn new() -> Self {
+        Self { words: [0; WORDS], len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Panics if `i` is not below `len()`.
+    pub fn get(&self, i: usize) -> bool {
+        assert!(i < self.len, "bit index out of range");
+        self.words[i / 32] & (1 << (31 - i % 32)) != 0
+    }
+
+    /// Panics if the sequence is full.
+    pub fn push(&mut self, bit: bool) {
+        assert!(self.len < MAX_FRAME_BITS, "too many bits");
+        if bit {
+            self.words[self.len / 32] |= 1 << (31 - self.len % 32);
+        }
+        self.len += 1;
+    }
+
+    fn push_value(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.push(value & (1 << i) != 0);
+        }
+    }
+
+    /// Flips bit `i`, e.g. to inject an error. Panics if it is out of range.
+    pub fn toggle(&mut self, i: usize) {
+        assert!(i < self.len, "bit index out of range");
+        self.words[i / 32] ^= 1 << (31 - i % 32);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(|i| self.get(i))
+    }
+}
+
+impl Default for Bits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for Bits {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for bit in self.iter() {
+            f.write_str(if bit { "1" } else { "0" })?;
+        }
+        Ok(())
+    }
+}
+
+/// Updates the CAN CRC-15 with one bit.
+fn crc15_bit(crc: u16, bit: bool) -> u16 {
+    let feedback = bit ^ (crc & 0x4000 != 0);
+    let crc = (crc << 1) & 0x7FFF;
+    if feedback {
+        crc ^ CRC15_POLY
+    } else {
+        crc
+    }
+}
+
+/// Inserts a stuff bit after five equal bits, the stuff bit itself counting
+/// towards the next run.
+struct Stuffer {
+    last: bool,
+    run: u32,
+}
+
+impl Stuffer {
+    const fn new() -> Self {
+        // The idle bus before SOF doesn't count.
+        Self { last: true, run: 0 }
+    }
+
+    fn push(&mut self, out: &mut Bits, bit: bool) {
+        self.track(bit);
+        out.push(bit);
+        if self.run == 5 {
+            self.track(!bit);
+            out.push(!bit);
+        }
+    }
+
+    fn track(&mut self, bit: bool) {
+        if bit == self.last {
+            self.run += 1;
+        } else {
+            self.last = bit;
+            self.run = 1;
+        }
+    }
+}
+
+/// The unstuffed bits from SOF to the end of the data field.
+fn unstuffed(frame: &CanFrame) -> Bits {
+    let mut bits = Bits::new();
+    bits.push(false); // SOF
+    let rtr = frame.is_remote_frame();
+    match frame.id() {
+        Id::Standard(id) => {
+            bits.push_value(id.as_raw() as u32, 11);
+            bits.push(rtr);
+            bits.push(false); // IDE
+            bits.push(false); // r0
+        }
+        Id::Extended(id) => {
+            let raw = id.as_raw();
+            bits.push_value(raw >> 18, 11);
+            bits.push(true); // SRR
+            bits.push(true); // IDE
+            bits.push_value(raw & 0x3FFFF, 18);
+            bits.push(rtr);
+            bits.push(false); // r1
+            bits.push(false); // r0
+        }
+    }
+    bits.push_value(frame.dlc() as u32, 4);
+    if !rtr {
+        for &byte in frame.data() {
+            bits.push_value(byte as u32, 8);
+        }
+    }
+    bits
+}
+
+/// CRC-15 of `frame` as sent in its CRC field.
+pub fn crc15(frame: &CanFrame) -> u16 {
+    unstuffed(frame).iter().fold(0, crc15_bit)
+}
+
+/// Stuffed bits from SOF through the CRC sequence.
+fn stuffed(frame: &CanFrame) -> Bits {
+    let header = unstuffed(frame);
+    let crc = header.iter().fold(0, crc15_bit);
+    let mut stuffer = Stuffer::new();
+    let mut bits = Bits::new();
+    for bit in header.iter() {
+        stuffer.push(&mut bits, bit);
+    }
+    for i in (0..15).rev() {
+        stuffer.push(&mut bits, crc & (1 << i) != 0);
+    }
+    bits
+}
+
+/// The bits a transmitter sends for `frame`, from SOF to the end of EOF. The
+/// ACK slot is recessive; the receivers overwrite it.
+pub fn encode(frame: &CanFrame) -> Bits {
+    let mut bits = stuffed(frame);
+    bits.push(true); // CRC delimiter
+    for _ in 0..TRAILER_BITS {
+        bits.push(true);
+    }
+    bits
+}
+
+/// `frame` in the layout can2040 keeps in `can2040_transmit::stuffed_data`:
+/// the stuffed bits from SOF through the CRC delimiter, MSB first, with the
+/// last word padded with recessive bits. Returns the words and how many are
+/// used.
+pub fn can2040_words(frame: &CanFrame) -> ([u32; WORDS], usize) {
+    let mut bits = stuffed(frame);
+    bits.push(true); // CRC delimiter
+    let used = bits.len().div_ceil(32);
+    let mut words = bits.words;
+    let extra = used * 32 - bits.len();
+    if extra > 0 {
+        words[used - 1] |= (1 << extra) - 1;
+    }
+    (words, used)
+}
+
+/// Why a frame could not be decoded. Bit positions count from SOF, including
+/// stuff bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// Six equal bits in the stuffed part of the frame.
+    Stuff {
+        bit: usize,
+    },
+    /// A fixed-form bit (CRC or ACK delimiter, EOF) was dominant.
+    Form {
+        bit: usize,
+    },
+    Crc {
+        received: u16,
+        computed: u16,
+    },
+    /// The bits ran out before the end of the frame.
+    Truncated,
+}
+
+/// A frame read back from the bus.
+#[derive(Clone, Copy, Debug)]
+pub struct Decoded {
+    pub frame: CanFrame,
+    /// The DLC field as sent; `frame` has at most 8 data bytes.
+    pub dlc: u8,
+    pub crc: u16,
+    /// Whether a receiver drove the ACK slot dominant.
+    pub acked: bool,
+    /// Position of the SOF in the bits the decoder was given.
+    pub start: usize,
+    /// Bits from SOF to the end of EOF.
+    pub len: usize,
+}
+
+/// Removes stuff bits and tracks the CRC while reading a frame.
+struct Reader<'a, I> {
+    bits: &'a mut I,
+    pos: &'a mut usize,
+    read: usize,
+    last: bool,
+    run: u32,
+    crc: u16,
+}
+
+impl<I: Iterator<Item = bool>> Reader<'_, I> {
+    fn raw(&mut self) -> Result<bool, DecodeError> {
+        let bit = self.bits.next().ok_or(DecodeError::Truncated)?;
+        *self.pos += 1;
+        self.read += 1;
+        Ok(bit)
+    }
+
+    /// Reads the stuff bit that is due after five equal bits, if any.
+    fn destuff(&mut self) -> Result<(), DecodeError> {
+        if self.run == 5 {
+            let stuff = self.raw()?;
+            if stuff == self.last {
+                return Err(DecodeError::Stuff { bit: self.read - 1 });
+            }
+            self.last = stuff;
+            self.run = 1;
+        }
+        Ok(())
+    }
+
+    /// Next bit of the stuffed part, without its stuff bits.
+    fn bit(&mut self) -> Result<bool, DecodeError> {
+        self.destuff()?;
+        let bit = self.raw()?;
+        if bit == self.last {
+            self.run += 1;
+        } else {
+            self.last = bit;
+            self.run = 1;
+        }
+        Ok(bit)
+    }
+
+    fn value(&mut self, count: u32, crc: bool) -> Result<u32, DecodeError> {
+        let mut value = 0;
+        for _ in 0..count {
+            let bit = self.bit()?;
+            if crc {
+                self.crc = crc15_bit(self.crc, bit);
+            }
+            value = value << 1 | bit as u32;
+        }
+        Ok(value)
+    }
+
+    /// A recessive fixed-form bit.
+    fn delimiter(&mut self) -> Result<(), DecodeError> {
+        match self.raw()? {
+            true => Ok(()),
+            false => Err(DecodeError::Form { bit: self.read - 1 }),
+        }
+    }
+}
+
+/// Decodes the frames in a stream of bus bits, one per item.
+///
+/// A frame is only looked for once the bus has been idle for 11 recessive
+/// bits, so a capture that starts in the middle of a frame, or a frame that
+/// failed to decode, is skipped up to the next idle period.
+pub struct Decoder<I> {
+    bits: I,
+    pos: usize,
+    idle: u32,
+    idle_needed: u32,
+}
+
+impl<I: Iterator<Item = bool>> Decoder<I> {
+    pub fn new(bits: impl IntoIterator<IntoIter = I>) -> Self {
+        Self { bits: bits.into_iter(), pos: 0, idle: 0, idle_needed: IDLE_BITS }
+    }
+
+    fn frame(&mut self, start: usize) -> Result<Decoded, DecodeError> {
+        let mut r = Reader {
+            bits: &mut self.bits,
+            pos: &mut self.pos,
+            read: 1,
+            last: false,
+            run: 1,
+            crc: crc15_bit(0, false),
+        };
+        let base = r.value(11, true)?;
+        let rtr_srr = r.value(1, true)? != 0;
+        let ide = r.value(1, true)? != 0;
+        let (id, rtr) = if ide {
+            let ext = r.value(18, true)?;
+            let rtr = r.value(1, true)? != 0;
+            r.value(2, true)?; // r1, r0
+            (Id::Extended(ExtendedId::new(base << 18 | ext).unwrap()), rtr)
+        } else {
+            r.value(1, true)?; // r0
+            (Id::Standard(StandardId::new(base as u16).unwrap()), rtr_srr)
+        };
+        let dlc = r.value(4, true)? as u8;
+        let len = (dlc as usize).min(8);
+        let mut data = [0u8; 8];
+        if !rtr {
+            for byte in &mut data[..len] {
+                *byte = r.value(8, true)? as u8;
+            }
+        }
+        let computed = r.crc;
+        let received = r.value(15, false)? as u16;
+        // Stuffing covers the CRC sequence up to its last bit.
+        r.destuff()?;
+        if received != computed {
+            return Err(DecodeError::Crc { received, computed });
+        }
+        r.delimiter()?;
+        let acked = !r.raw()?;
+        for _ in 0..1 + 7 {
+            r.delimiter()?;
+        }
+
+        let frame = match rtr {
+            true => CanFrame::new_remote(id, len),
+            false => CanFrame::new(id, &data[..len]),
+        };
+        Ok(Decoded { frame: frame.unwrap(), dlc, crc: computed, acked, start, len: r.read })
+    }
+}
+
+impl<I: Iterator<Item = bool>> Iterator for Decoder<I> {
+    type Item = Result<Decoded, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let bit = self.bits.next()?;
+            self.pos += 1;
+            if bit {
+                self.idle += 1;
+                continue;
+            }
+            let idle = core::mem::take(&mut self.idle);
+            if idle < self.idle_needed {
+                continue;
+            }
+            let result = self.frame(self.pos - 1);
+            // EOF already counts towards the next idle period.
+            self.idle_needed = if result.is_ok() { IDLE_BITS - 8 } else { IDLE_BITS };
+            return match result {
+                Err(DecodeError::Truncated) => None,
+                result => Some(result),
+            };
+        }
+    }
+}
+
+/// Recovers bus bits from a logic analyser capture sampled at a fixed rate,
+/// e.g. the RX or CAN-H line exported as raw samples.
+///
+/// Like a CAN controller it resynchronises on every recessive to dominant
+/// edge and samples each bit in its middle, so it tolerates the clock
+/// mismatch between the analyser and the bus. At least 8 samples per bit
+/// are recommended.
+pub struct Sampler<I> {
+    samples: I,
+    /// Samples per bit, in 1/256 samples.
+    bit_len: u64,
+    /// Time since the last edge and next sample point, in 1/256 samples.
+    time: u64,
+    next_sample: u64,
+    last: bool,
+}
+
+impl<I: Iterator<Item = bool>> Sampler<I> {
+    pub fn new(samples: impl IntoIterator<IntoIter = I>, sample_rate: u32, bitrate: u32) -> Self {
+        let bit_len = ((sample_rate as u64) << 8) / bitrate as u64;
+        Self {
+            samples: samples.into_iter(),
+            bit_len,
+            time: 0,
+            next_sample: bit_len / 2,
+            last: true,
+        }
+    }
+}
+
+impl<I: Iterator<Item = bool>> Iterator for Sampler<I> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<bool> {
+        loop {
+            let level = self.samples.next()?;
+            if self.last && !level {
+                self.time = 0;
+                self.next_sample = self.bit_len / 2;
+            }
+            self.last = level;
+            self.time += 256;
+            if self.time > self.next_sample {
+                self.next_sample += self.bit_len;
+                return Some(level);
+            }
+        }
+    }
+}
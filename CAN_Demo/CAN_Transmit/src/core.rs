@@ -1,26 +1,30 @@
-extern crate alloc;
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::mem::ManuallyDrop;
+use core::ptr;
 
-use alloc::fmt;
-use alloc::vec::Vec;
-use core::cell::RefCell;
-
-use rp2040_hal::pac;
 use cortex_m::asm::wfi;
 use cortex_m::interrupt::Mutex;
-use defmt::{debug, Format};
-use embedded_can::{ErrorKind, Id, StandardId};
-use rp2040_hal::pac::{interrupt, Interrupt};
-
-use crate::core::can2040_lib::{
-    can2040, can2040_bitunstuffer, can2040_callback_config, can2040_check_transmit, can2040_msg,
-    can2040_msg__bindgen_ty_1, can2040_pio_irq_handler, can2040_setup, can2040_start,
-    can2040_transmit, CAN2040_NOTIFY_RX,
+use defmt::debug;
+use rp2040_hal::clocks::SystemClock;
+use rp2040_hal::gpio::{DynPinId, Function, Pin, PinId, PullNone, PullType, PullUp, ValidFunction};
+use rp2040_hal::pac;
+#[cfg(not(feature = "rtic"))]
+use rp2040_hal::pac::interrupt;
+use rp2040_hal::pio::PIOExt;
+use rp2040_hal::Clock;
+
+use crate::asynch::WakerSlot;
+use crate::can2040_lib::{
+    can2040, can2040_bitunstuffer, can2040_callback_config, can2040_msg, can2040_msg__bindgen_ty_1,
+    can2040_pio_irq_handler, can2040_setup, can2040_start, can2040_stop, can2040_transmit,
+    CAN2040_NOTIFY_ERROR, CAN2040_NOTIFY_RX, CAN2040_NOTIFY_TX,
 };
-
-#[allow(warnings)]
-mod can2040_lib {
-    include!(concat!(env!("OUT_DIR"), "/can2040_lib.rs"));
-}
+use crate::filter::{Filter, FilterBanks};
+use crate::frame::{CanError, CanFrame, TimestampedFrame, TX_TIMEOUT_US};
+use crate::ring_buffer::{OverflowPolicy, RingBuffer};
+use crate::stats::{frame_bits, BusLoad, CanStats};
+use crate::tx_queue::TxQueue;
+use crate::{RX_QUEUE_LEN, TX_QUEUE_LEN};
 
 impl can2040_bitunstuffer {
     pub fn new() -> Self {
@@ -28,14 +32,6 @@ impl can2040_bitunstuffer {
     }
 }
 
-impl can2040_msg__bindgen_ty_1 {
-    pub fn new() -> Self {
-        Self {
-            data: [0; 8], // 这里使用data字段初始化
-        }
-    }
-}
-
 impl can2040_msg {
     pub fn new() -> Self {
         Self { id: 0, dlc: 0, __bindgen_anon_1: can2040_msg__bindgen_ty_1::new() }
@@ -72,173 +68,833 @@ impl can2040 {
     }
 }
 
-pub struct Can2040 {}
+/// Bitrates [`Can2040::detect_bitrate`] tries, in this order.
+pub const STANDARD_BITRATES: [u32; 7] =
+    [10_000, 20_000, 50_000, 125_000, 250_000, 500_000, 1_000_000];
+
+/// Runs the C library's `can2040_transmit` for `frame` on a scratch instance
+/// and returns the CRC and the stuffed words it computed, with the number of
+/// words used. Only for `examples/bitstream_check.rs`, which compares
+/// [`bitstream`](crate::bitstream) with can2040; no PIO is touched.
+#[cfg(feature = "bitstream-check")]
+#[doc(hidden)]
+pub fn can2040_reference(frame: &CanFrame) -> (u16, [u32; 5], usize) {
+    // Stands in for the PIO register block: `can2040_transmit` writes
+    // IRQ_FORCE to wake the state machine.
+    let mut pio_regs = [0u32; 0x144 / 4];
+    let mut cd = can2040::new();
+    cd.pio_hw = pio_regs.as_mut_ptr().cast();
+    let mut msg = frame.0;
+    unsafe { can2040_transmit(&mut cd, &mut msg) };
+    let queued = &cd.tx_queue[0];
+    (queued.crc as u16, queued.stuffed_data, queued.stuffed_words as usize)
+}
+
+/// Handle to a can2040 bus running on the PIO block `P` (`pac::PIO0` or
+/// `pac::PIO1`). Each PIO can host one bus, with its own receive queue and
+/// interrupt handler. The handle owns the PIO and both pins; dropping it stops
+/// the bus, and [`Can2040::release`] gives them back.
+pub struct Can2040<P: Instance = pac::PIO0> {
+    pio: P,
+    rx: Pin<DynPinId, P::PinFunction, PullUp>,
+    tx: Pin<DynPinId, P::PinFunction, PullNone>,
+    sys_clock: u32,
+    bitrate: u32,
+    listen_only: bool,
+}
 
-#[derive(Debug, defmt::Format)]
-pub enum CanError {
-    TransmissionError,
-    ReceptionError,
+/// Snapshot of the transmit side and the last error reported by can2040, see
+/// [`Can2040::status`]. Error, overflow and filter counts are in
+/// [`Can2040::stats`].
+///
+/// A disconnected or unterminated bus shows up as `tx_pending` staying above
+/// zero while `tx_complete` stops advancing, since nobody acknowledges the
+/// frames and can2040 keeps retrying them.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct CanStatus {
+    /// Frames acknowledged on the bus (`CAN2040_NOTIFY_TX`).
+    pub tx_complete: u32,
+    /// Frames queued for transmission that have not been acknowledged yet.
+    pub tx_pending: u32,
+    /// The most recently completed frame.
+    pub last_tx: Option<CanFrame>,
+    /// The most recent error. It is cleared once `receive` has reported it.
+    pub last_error: Option<CanError>,
 }
 
-impl embedded_can::Error for CanError {
-    fn kind(&self) -> ErrorKind {
-        todo!()
+impl CanStatus {
+    const fn new() -> Self {
+        Self { tx_complete: 0, tx_pending: 0, last_tx: None, last_error: None }
     }
 }
 
-impl fmt::Debug for can2040_msg {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        unsafe {
-            write!(
-                f,
-                "can2040_msg(D) {{ id: {:x?}, dlc: {:x?}, data: {:x?} }}",
-                self.id,
-                self.dlc,
-                &self.__bindgen_anon_1.data[..self.dlc as usize]
-            )
-        }
+/// Counters behind [`Can2040::stats`], updated from the callback.
+struct Traffic {
+    stats: CanStats,
+    load: BusLoad,
+    bitrate: u32,
+    /// `rx_queue.overflows()` at the last reset.
+    overflow_base: u32,
+}
+
+impl Traffic {
+    const fn new() -> Self {
+        Self { stats: CanStats::new(), load: BusLoad::new(), bitrate: 0, overflow_base: 0 }
     }
 }
 
-impl defmt::Format for can2040_msg {
-    fn format(&self, f: defmt::Formatter) {
-        unsafe {
-            defmt::write!(
-                f,
-                "can2040_msg(F) {{ id: {:x}, dlc: {:x}, data: {:x} }}",
-                self.id,
-                self.dlc,
-                &self.__bindgen_anon_1.data[..self.dlc as usize]
-            )
+/// Microseconds since the TIMER was last reset, from its free-running 64-bit
+/// counter.
+fn now_us() -> u64 {
+    // SAFETY: only reads the raw counter, which has no side effects.
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    loop {
+        let hi = timer.timerawh.read().bits();
+        let lo = timer.timerawl.read().bits();
+        if timer.timerawh.read().bits() == hi {
+            return (hi as u64) << 32 | lo as u64;
         }
     }
 }
 
-#[derive(Clone, Debug, Format)]
-pub struct CanFrame(can2040_msg);
+/// Takes the TIMER behind [`now_us`] out of reset, in case the application
+/// hasn't created a `hal::Timer`. This doesn't disturb a counter that is
+/// already running.
+fn start_timer(resets: &mut pac::RESETS) {
+    resets.reset.modify(|_, w| w.timer().clear_bit());
+    while resets.reset_done.read().timer().bit_is_clear() {}
+}
+
+/// Transmit side of a bus: the software queue, and whether can2040 holds a
+/// frame. It is given at most one at a time so that the queue's priority order
+/// decides what goes out next.
+struct TxState {
+    queue: TxQueue<TX_QUEUE_LEN>,
+    in_flight: bool,
+}
 
-impl embedded_can::Frame for CanFrame {
-    fn new(id: impl Into<embedded_can::Id>, data: &[u8]) -> Option<Self> {
-        if data.len() > 8 {
-            return None;
+impl TxState {
+    const fn new() -> Self {
+        Self { queue: TxQueue::new(), in_flight: false }
+    }
+}
+
+/// The C library's state for one bus.
+pub(crate) struct Cbus(can2040);
+
+/// Holds the bus's can2040 instance. Thread code reaches it through
+/// [`Bus::with_cbus`], which masks `PIOx_IRQ_0` meanwhile, and the interrupt
+/// handler through [`Bus::service`]. The handler runs at priority 0, or with
+/// `rtic` under the lock on the `Can2040` handle, so nothing that touches the
+/// instance preempts it.
+pub(crate) struct CbusCell(UnsafeCell<Option<Cbus>>);
+
+// SAFETY: see above; the only pointer in `can2040` is `pio_hw`, the address of
+// the PIO block's registers, which is valid from any context.
+unsafe impl Sync for CbusCell {}
+
+mod sealed {
+    use super::*;
+
+    /// Driver state for one PIO block, shared between its `Can2040` handle and
+    /// its interrupt handler.
+    pub struct Bus {
+        /// Never accessed from the can2040 callback, which is given the
+        /// instance by the C library instead.
+        pub(super) cbus: CbusCell,
+        pub(super) irq: pac::Interrupt,
+        pub(super) rx_queue: RingBuffer<TimestampedFrame, RX_QUEUE_LEN>,
+        pub(super) status: Mutex<Cell<CanStatus>>,
+        pub(super) filters: Mutex<Cell<FilterBanks>>,
+        pub(super) loopback: Mutex<Cell<bool>>,
+        pub(super) traffic: Mutex<RefCell<Traffic>>,
+        pub(super) tx: Mutex<RefCell<TxState>>,
+        pub(super) rx_waker: WakerSlot,
+        pub(super) tx_waker: WakerSlot,
+    }
+
+    impl Bus {
+        pub(super) const fn new(irq: pac::Interrupt) -> Self {
+            Self {
+                cbus: CbusCell(UnsafeCell::new(None)),
+                irq,
+                rx_queue: RingBuffer::new(OverflowPolicy::DropNewest),
+                status: Mutex::new(Cell::new(CanStatus::new())),
+                filters: Mutex::new(Cell::new(FilterBanks::new())),
+                loopback: Mutex::new(Cell::new(false)),
+                traffic: Mutex::new(RefCell::new(Traffic::new())),
+                tx: Mutex::new(RefCell::new(TxState::new())),
+                rx_waker: WakerSlot::new(),
+                tx_waker: WakerSlot::new(),
+            }
         }
+    }
 
-        let mut data_arr = [0u8; 8];
-        data_arr[..data.len()].copy_from_slice(data);
-
-        Some(CanFrame {
-            0: can2040_msg {
-                id: match id.into() {
-                    Id::Standard(sid) => sid.as_raw() as u32,
-                    Id::Extended(_) => return None,
-                },
-                dlc: data.len() as u32,
-                __bindgen_anon_1: can2040_msg__bindgen_ty_1 { data: data_arr },
-            },
-        })
+    pub trait Instance {
+        const PIO_NUM: u32;
+        const IRQ: pac::Interrupt;
+
+        fn bus() -> &'static Bus;
+    }
+}
+
+use sealed::Bus;
+
+/// A PIO block that can host a can2040 bus.
+pub trait Instance: sealed::Instance + PIOExt {}
+
+static BUS0: Bus = Bus::new(pac::Interrupt::PIO0_IRQ_0);
+static BUS1: Bus = Bus::new(pac::Interrupt::PIO1_IRQ_0);
+
+impl sealed::Instance for pac::PIO0 {
+    const PIO_NUM: u32 = 0;
+    const IRQ: pac::Interrupt = pac::Interrupt::PIO0_IRQ_0;
+
+    fn bus() -> &'static Bus {
+        &BUS0
+    }
+}
+
+impl Instance for pac::PIO0 {}
+
+impl sealed::Instance for pac::PIO1 {
+    const PIO_NUM: u32 = 1;
+    const IRQ: pac::Interrupt = pac::Interrupt::PIO1_IRQ_0;
+
+    fn bus() -> &'static Bus {
+        &BUS1
     }
+}
 
-    fn new_remote(_id: impl Into<Id>, _dlc: usize) -> Option<Self> {
-        todo!()
+impl Instance for pac::PIO1 {}
+
+impl Bus {
+    /// Runs `f` on the can2040 slot with `PIOx_IRQ_0` masked, so the
+    /// interrupt handler can't run meanwhile while every other interrupt can.
+    /// The interrupt is unmasked again only if it was unmasked before.
+    fn with_cbus<R>(&self, f: impl FnOnce(&mut Option<Cbus>) -> R) -> R {
+        let unmasked = pac::NVIC::is_enabled(self.irq);
+        pac::NVIC::mask(self.irq);
+        // SAFETY: with the interrupt masked only thread code can get here, and
+        // `f` doesn't call back into `with_cbus`.
+        let ret = f(unsafe { &mut *self.cbus.0.get() });
+        if unmasked {
+            // SAFETY: restores the mask as it was.
+            unsafe { pac::NVIC::unmask(self.irq) };
+        }
+        ret
     }
 
-    fn is_extended(&self) -> bool {
-        false
+    /// Runs the C library's interrupt handler, if the bus is started. The
+    /// callback it calls only uses the instance it is passed.
+    ///
+    /// # Safety
+    ///
+    /// Only from `PIOx_IRQ_0`, or where it can't run, see [`CbusCell`].
+    unsafe fn service(&self) {
+        // SAFETY: thread code masks the interrupt around its own accesses.
+        if let Some(cbus) = unsafe { &mut *self.cbus.0.get() } {
+            unsafe { can2040_pio_irq_handler(&mut cbus.0) };
+        }
     }
 
-    fn is_remote_frame(&self) -> bool {
-        false
+    fn is_running(&self) -> bool {
+        self.with_cbus(|cbus| cbus.is_some())
     }
 
-    fn id(&self) -> embedded_can::Id {
-        embedded_can::Id::Standard(StandardId::new(self.0.id as u16).unwrap())
+    fn update_status<R>(&self, f: impl FnOnce(&mut CanStatus) -> R) -> R {
+        cortex_m::interrupt::free(|cs| {
+            let cell = self.status.borrow(cs);
+            let mut status = cell.get();
+            let ret = f(&mut status);
+            cell.set(status);
+            ret
+        })
+    }
+
+    /// Hands `frame` to can2040 if it is idle, or else queues it. With
+    /// `displace`, a full queue gives up its lowest-priority frame if `frame`
+    /// outranks it, and that frame is returned.
+    fn submit(&self, frame: &CanFrame, displace: bool) -> nb::Result<Option<CanFrame>, CanError> {
+        self.with_cbus(|cbus| {
+            let cbus = cbus.as_mut().ok_or(nb::Error::Other(CanError::NotInitialized))?;
+            cortex_m::interrupt::free(|cs| {
+                let mut tx = self.tx.borrow(cs).borrow_mut();
+                let result = if !tx.in_flight {
+                    // critical path
+                    let mut msg = frame.0;
+                    // SAFETY: can2040 copies the message into its own queue.
+                    unsafe { can2040_transmit(&mut cbus.0, &mut msg) };
+                    tx.in_flight = true;
+                    Ok(None)
+                } else if displace {
+                    tx.queue.push_displacing(*frame)
+                } else {
+                    tx.queue.push(*frame).map(|()| None)
+                };
+
+                let displaced = result.map_err(|_| nb::Error::WouldBlock)?;
+                let cell = self.status.borrow(cs);
+                let mut status = cell.get();
+                if displaced.is_none() {
+                    status.tx_pending += 1;
+                }
+                cell.set(status);
+                Ok(displaced)
+            })
+        })
     }
 
-    fn dlc(&self) -> usize {
-        self.0.dlc as usize
+    /// Gives can2040 the next queued frame once it has finished the last one.
+    /// `cd` is the instance the callback was called for.
+    fn transmit_next(&self, cd: *mut can2040) {
+        cortex_m::interrupt::free(|cs| {
+            let mut tx = self.tx.borrow(cs).borrow_mut();
+            match tx.queue.pop() {
+                Some(next) => {
+                    let mut msg = next.0;
+                    // SAFETY: can2040 allows queueing a frame from its callback,
+                    // and copies it.
+                    unsafe { can2040_transmit(cd, &mut msg) };
+                }
+                None => tx.in_flight = false,
+            }
+        })
     }
 
-    fn data(&self) -> &[u8] {
-        // 假设您可以从 __bindgen_anon_1 字段获取数据的byte slice
-        unsafe { &self.0.__bindgen_anon_1.data[0..self.0.dlc as usize] }
+    fn notify(&self, cd: *mut can2040, notify: u32, msg: can2040_msg) {
+        if notify == CAN2040_NOTIFY_RX {
+            let frame = CanFrame(msg);
+            let now = now_us();
+            let accepted = cortex_m::interrupt::free(|cs| {
+                let accepted = self.filters.borrow(cs).get().accepts(&frame);
+                let mut traffic = self.traffic.borrow(cs).borrow_mut();
+                traffic.load.record(now, frame_bits(&frame));
+                let stats = &mut traffic.stats;
+                stats.rx_frames = stats.rx_frames.wrapping_add(1);
+                stats.rx_bytes = stats.rx_bytes.wrapping_add(frame.0.data_len() as u32);
+                if !accepted {
+                    stats.rx_filtered = stats.rx_filtered.wrapping_add(1);
+                }
+                accepted
+            });
+            if accepted {
+                let received = TimestampedFrame { frame, timestamp_us: now, echo: false };
+                // SAFETY: the callback, inside the interrupt handler, is the
+                // only producer.
+                unsafe { self.rx_queue.push(received) };
+                self.rx_waker.wake();
+            }
+        } else if notify == CAN2040_NOTIFY_TX {
+            let frame = CanFrame(msg);
+            let now = now_us();
+            let echo = cortex_m::interrupt::free(|cs| {
+                let mut traffic = self.traffic.borrow(cs).borrow_mut();
+                traffic.load.record(now, frame_bits(&frame));
+                let stats = &mut traffic.stats;
+                stats.tx_frames = stats.tx_frames.wrapping_add(1);
+                stats.tx_bytes = stats.tx_bytes.wrapping_add(frame.0.data_len() as u32);
+                self.loopback.borrow(cs).get() && self.filters.borrow(cs).get().accepts(&frame)
+            });
+            self.update_status(|status| {
+                status.tx_complete = status.tx_complete.wrapping_add(1);
+                status.tx_pending = status.tx_pending.saturating_sub(1);
+                status.last_tx = Some(frame);
+            });
+            self.transmit_next(cd);
+            self.tx_waker.wake();
+            if echo {
+                let received = TimestampedFrame { frame, timestamp_us: now, echo: true };
+                // SAFETY: as above.
+                unsafe { self.rx_queue.push(received) };
+                self.rx_waker.wake();
+            }
+        } else if notify & CAN2040_NOTIFY_ERROR != 0 {
+            // The low bits carry an error code, which can2040 currently only uses
+            // for receive FIFO overflows.
+            self.update_status(|status| status.last_error = Some(CanError::Overrun));
+            cortex_m::interrupt::free(|cs| {
+                let stats = &mut self.traffic.borrow(cs).borrow_mut().stats;
+                stats.errors = stats.errors.wrapping_add(1);
+            });
+            // `receive` reports the error; wake it so it isn't held up until
+            // the next frame.
+            self.rx_waker.wake();
+        }
     }
 }
 
-static RECEIVE_QUEUE: Mutex<RefCell<Vec<CanFrame>>> = Mutex::new(RefCell::new(Vec::new()));
+unsafe extern "C" fn can2040_cb<P: Instance>(cd: *mut can2040, notify: u32, msg: *mut can2040_msg) {
+    // SAFETY: can2040 passes a valid message for the duration of the call.
+    let msg = *msg;
+    debug!("xfguo: can2040_cb {}, notify = {:x}, msg = {:?}", P::PIO_NUM, notify, msg);
+    P::bus().notify(cd, notify, msg);
+}
 
-unsafe extern "C" fn can2040_cb(_cd: *mut can2040, notify: u32, msg: *mut can2040_msg) {
-    debug!("xfguo: can2040_cb 0, notify = {:x}, msg = {:?}", notify, *msg);
-    if notify == CAN2040_NOTIFY_RX {
-        let msg_copy = *msg.clone();
+impl<P: Instance> Can2040<P> {
+    /// Starts can2040 on `pio` at `bitrate` bit/s and unmasks its `PIOx_IRQ_0`
+    /// interrupt.
+    ///
+    /// The C library drives the whole PIO block and reconfigures both pins, so
+    /// they are taken by value and switched to the PIO's pin function here. The
+    /// bit timing is derived from the actual frequency of `system_clock`.
+    /// `resets` takes the TIMER, used for timestamps and the bus load, out of
+    /// reset if no `hal::Timer` has done so yet. The interrupt priority is
+    /// forced to 0 so that bit timing gets the most real-time response.
+    ///
+    /// With the `rtic` feature `nvic` is left alone: the application binds
+    /// `PIOx_IRQ_0` to a hardware task, which sets its priority and unmasks it,
+    /// and calls [`Can2040::on_interrupt`] from there. The signature is the
+    /// same either way, so enabling the feature doesn't break callers.
+    pub fn new<RX, FRX, MRX, TX, FTX, MTX>(
+        pio: P,
+        rx: Pin<RX, FRX, MRX>,
+        tx: Pin<TX, FTX, MTX>,
+        bitrate: u32,
+        system_clock: &SystemClock,
+        resets: &mut pac::RESETS,
+        nvic: &mut pac::NVIC,
+    ) -> Self
+    where
+        RX: PinId + ValidFunction<P::PinFunction>,
+        FRX: Function,
+        MRX: PullType,
+        TX: PinId + ValidFunction<P::PinFunction>,
+        FTX: Function,
+        MTX: PullType,
+    {
+        start_timer(resets);
+        Self::init(
+            pio,
+            rx.into_function().into_pull_type().into_dyn_pin(),
+            tx.into_function().into_pull_type().into_dyn_pin(),
+            bitrate,
+            system_clock.freq().to_Hz(),
+            false,
+            nvic,
+        )
+    }
+
+    /// Like [`Can2040::new`], but starts the bus in listen-only mode, see
+    /// [`Can2040::set_listen_only`], so TX is never driven, not even before
+    /// the first call into the handle.
+    pub fn new_listen_only<RX, FRX, MRX, TX, FTX, MTX>(
+        pio: P,
+        rx: Pin<RX, FRX, MRX>,
+        tx: Pin<TX, FTX, MTX>,
+        bitrate: u32,
+        system_clock: &SystemClock,
+        resets: &mut pac::RESETS,
+        nvic: &mut pac::NVIC,
+    ) -> Self
+    where
+        RX: PinId + ValidFunction<P::PinFunction>,
+        FRX: Function,
+        MRX: PullType,
+        TX: PinId + ValidFunction<P::PinFunction>,
+        FTX: Function,
+        MTX: PullType,
+    {
+        start_timer(resets);
+        Self::init(
+            pio,
+            rx.into_function().into_pull_type().into_dyn_pin(),
+            tx.into_function().into_pull_type().into_dyn_pin(),
+            bitrate,
+            system_clock.freq().to_Hz(),
+            true,
+            nvic,
+        )
+    }
+
+    fn init(
+        pio: P,
+        rx: Pin<DynPinId, P::PinFunction, PullUp>,
+        tx: Pin<DynPinId, P::PinFunction, PullNone>,
+        bitrate: u32,
+        sys_clock: u32,
+        listen_only: bool,
+        nvic: &mut pac::NVIC,
+    ) -> Self {
+        let bus = P::bus();
         cortex_m::interrupt::free(|cs| {
-            RECEIVE_QUEUE.borrow(cs).borrow_mut().push(CanFrame(msg_copy));
+            let mut traffic = bus.traffic.borrow(cs).borrow_mut();
+            *traffic = Traffic::new();
+            traffic.overflow_base = bus.rx_queue.overflows();
         });
+        let mut can = Can2040 { pio, rx, tx, sys_clock, bitrate, listen_only };
+        can.start(bitrate);
+
+        // Enable interrupts and set priority for it.
+        #[cfg(not(feature = "rtic"))]
+        unsafe {
+            nvic.set_priority(P::IRQ, 0);
+            pac::NVIC::unmask(P::IRQ);
+        }
+        #[cfg(feature = "rtic")]
+        let _ = nvic;
+        can
     }
-    // TODO(zephyr): More code for TX / Err
-}
 
-impl embedded_can::nb::Can for Can2040 {
-    type Frame = CanFrame;
-    type Error = CanError;
+    /// Gives the bus a fresh can2040 instance and starts it at `bitrate`. The
+    /// bus must be stopped.
+    fn start(&mut self, bitrate: u32) {
+        let bus = P::bus();
+        cortex_m::interrupt::free(|cs| {
+            let mut traffic = bus.traffic.borrow(cs).borrow_mut();
+            traffic.load = BusLoad::new();
+            traffic.bitrate = bitrate;
+        });
+        self.bitrate = bitrate;
+        let (rx, tx) = (self.rx.id().num as u32, self.tx.id().num as u32);
+        let sys_clock = self.sys_clock;
+        bus.with_cbus(|cbus| {
+            // Owning `pio` means nobody else has started a bus on it.
+            let cd = &mut cbus.insert(Cbus(can2040::new())).0;
+            // SAFETY: `cd` stays at this address in the static `Bus` until
+            // `stop` drops it.
+            unsafe {
+                can2040_setup(cd, P::PIO_NUM);
+                can2040_callback_config(cd, Some(can2040_cb::<P>));
+                can2040_start(cd, sys_clock, bitrate, rx, tx);
+            }
+        });
+        if self.listen_only {
+            // can2040 drives TX for acknowledgements, so hold the pin recessive
+            // (high). `can2040_start` has just rewritten GPIO_CTRL, clearing any
+            // earlier override.
+            // SAFETY: only touches the control register of the owned TX pin.
+            let io = unsafe { &*pac::IO_BANK0::ptr() };
+            io.gpio[tx as usize].gpio_ctrl.modify(|_, w| w.outover().high());
+        }
+    }
 
-    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Self::Error> {
+    /// Stops can2040 and masks `PIOx_IRQ_0`. Frames waiting to be sent are
+    /// discarded, while received frames stay in the queue until read; after
+    /// that `receive` and `transmit` fail with `CanError::NotInitialized`,
+    /// including `AsyncCan` calls already waiting. Stopping a stopped bus does
+    /// nothing.
+    ///
+    /// With the `rtic` feature the mask is left to RTIC, which also uses it
+    /// for locks; the hardware task finds nothing to service meanwhile.
+    pub fn stop(&mut self) {
+        let bus = P::bus();
+        #[cfg(not(feature = "rtic"))]
+        pac::NVIC::mask(P::IRQ);
+        if let Some(mut cbus) = bus.with_cbus(Option::take) {
+            unsafe { can2040_stop(&mut cbus.0) };
+        }
+        cortex_m::interrupt::free(|cs| *bus.tx.borrow(cs).borrow_mut() = TxState::new());
+        bus.update_status(|status| status.tx_pending = 0);
+        // Pending futures see `NotInitialized` instead of waiting forever.
+        bus.rx_waker.wake();
+        bus.tx_waker.wake();
+    }
+
+    /// Stops the bus if it is running and starts it again at `bitrate`, e.g.
+    /// to follow a bitrate change requested by a host. The statistics are kept
+    /// and the bus load is measured against the new bitrate. `AsyncCan` calls
+    /// already waiting carry on with the restarted bus.
+    ///
+    /// `PIOx_IRQ_0` is unmasked again with the priority it had. With the
+    /// `rtic` feature it is left as it was, since a lock on the resource
+    /// holding the bus may have masked it and the hardware task must not run
+    /// until the lock is released.
+    pub fn restart(&mut self, bitrate: u32) {
+        self.stop();
+        self.start(bitrate);
+        // SAFETY: `start` has set up the instance the handler services.
+        #[cfg(not(feature = "rtic"))]
         unsafe {
-            if let Some(cbus) = CBUS.as_mut() {
-                let cbus_ptr = &mut *cbus as *mut _;
-                if can2040_check_transmit(cbus_ptr) == 0 {
-                    Err(nb::Error::WouldBlock)
-                } else {
-                    // critical path
-                    can2040_transmit(cbus_ptr, frame as *const _ as *mut _);
-                    Ok(None)
+            pac::NVIC::unmask(P::IRQ)
+        };
+    }
+
+    /// Whether the bus is started, i.e. not [`stop`](Self::stop)ped.
+    pub fn is_running(&self) -> bool {
+        P::bus().is_running()
+    }
+
+    /// The bitrate the bus was last started with.
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate
+    }
+
+    /// Switches listen-only mode on or off. In listen-only mode the TX pin is
+    /// held recessive, so the node neither acknowledges frames nor signals
+    /// anything else on the bus, and transmitting fails with
+    /// `CanError::ListenOnly`. A running bus is restarted, which discards
+    /// frames waiting to be sent.
+    pub fn set_listen_only(&mut self, listen_only: bool) {
+        self.listen_only = listen_only;
+        if self.is_running() {
+            self.restart(self.bitrate);
+        }
+    }
+
+    pub fn is_listen_only(&self) -> bool {
+        self.listen_only
+    }
+
+    /// Attaches to a bus of unknown bitrate: switches to listen-only mode and
+    /// tries each of [`STANDARD_BITRATES`] for `dwell_us` microseconds until a
+    /// frame is received, which can2040 only reports once its CRC checked out.
+    ///
+    /// Returns the bitrate found, with the bus left running at it in
+    /// listen-only mode and the frame in the receive queue; call
+    /// `set_listen_only(false)` to take part in the bus. Returns `None` and
+    /// stops the bus if no bitrate worked, e.g. because the bus was idle.
+    /// `dwell_us` should cover at least the period of the slowest cyclic frame
+    /// expected.
+    pub fn detect_bitrate(&mut self, dwell_us: u64) -> Option<u32> {
+        let bus = P::bus();
+        let rx_frames =
+            || cortex_m::interrupt::free(|cs| bus.traffic.borrow(cs).borrow().stats.rx_frames);
+        // With the `rtic` feature the PIO is serviced here instead, so the
+        // hardware task is kept masked for the whole probe whatever the
+        // ceiling of the resource holding the bus: below the maximum priority
+        // the lock has masked it already, at the maximum the lock runs with
+        // interrupts disabled, and either way masking it again is harmless.
+        // The caller can't be the hardware task in the middle of servicing,
+        // since `on_interrupt` also takes `&mut self`.
+        #[cfg(feature = "rtic")]
+        let unmasked = pac::NVIC::is_enabled(P::IRQ);
+        #[cfg(feature = "rtic")]
+        pac::NVIC::mask(P::IRQ);
+
+        self.listen_only = true;
+        let found = STANDARD_BITRATES.into_iter().find(|&bitrate| {
+            self.restart(bitrate);
+            let (start, seen) = (now_us(), rx_frames());
+            while now_us().wrapping_sub(start) < dwell_us {
+                // SAFETY: the interrupt is masked, so this is the only call.
+                #[cfg(feature = "rtic")]
+                unsafe {
+                    irq_handler::<P>()
+                };
+                if rx_frames() != seen {
+                    return true;
                 }
-            } else {
-                Err(nb::Error::Other(CanError::TransmissionError))
             }
+            false
+        });
+        if found.is_none() {
+            self.stop();
         }
+
+        #[cfg(feature = "rtic")]
+        if unmasked {
+            // SAFETY: restores the mask as it was.
+            unsafe { pac::NVIC::unmask(P::IRQ) };
+        }
+        found
     }
 
-    fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
+    /// Stops the bus and hands back the PIO block and the pins, still set to
+    /// the PIO function, so they can be given to another driver.
+    #[allow(clippy::type_complexity)]
+    pub fn release(
+        self,
+    ) -> (P, Pin<DynPinId, P::PinFunction, PullUp>, Pin<DynPinId, P::PinFunction, PullNone>) {
+        let mut this = ManuallyDrop::new(self);
+        this.stop();
+        // SAFETY: `this` is never dropped, so each field is moved out once.
+        unsafe { (ptr::read(&this.pio), ptr::read(&this.rx), ptr::read(&this.tx)) }
+    }
+
+    /// Services the PIO. Call this from the application's handler for
+    /// `PIOx_IRQ_0`, e.g. `#[task(binds = PIO0_IRQ_0, shared = [can])]`, then
+    /// drain the frames it queued with `receive` and pass them on to software
+    /// tasks.
+    #[cfg(feature = "rtic")]
+    pub fn on_interrupt(&mut self) {
+        // SAFETY: `&mut self` keeps every other method, and with it every
+        // other access to the instance, out.
+        unsafe { irq_handler::<P>() };
+    }
+
+    /// Returns the TX completion counters and the last error collected from
+    /// the can2040 notifications.
+    pub fn status(&self) -> CanStatus {
+        let bus = P::bus();
+        cortex_m::interrupt::free(|cs| bus.status.borrow(cs).get())
+    }
+
+    /// Traffic counters since the bus was started or `reset_stats` was last
+    /// called, and the current bus load.
+    pub fn stats(&self) -> CanStats {
+        let bus = P::bus();
+        let now = now_us();
         cortex_m::interrupt::free(|cs| {
-            let mut queue = RECEIVE_QUEUE.borrow(cs).borrow_mut();
-            if queue.is_empty() {
-                Err(nb::Error::WouldBlock)
-            } else {
-                return Ok(queue.remove(0));
-            }
+            let mut traffic = bus.traffic.borrow(cs).borrow_mut();
+            let bitrate = traffic.bitrate;
+            let mut stats = traffic.stats;
+            stats.rx_overflows = bus.rx_queue.overflows().wrapping_sub(traffic.overflow_base);
+            stats.bus_load = traffic.load.permille(now, bitrate);
+            stats
+        })
+    }
+
+    /// Zeroes the counters returned by `stats`. The bus load keeps its window.
+    pub fn reset_stats(&mut self) {
+        let bus = P::bus();
+        cortex_m::interrupt::free(|cs| {
+            let mut traffic = bus.traffic.borrow(cs).borrow_mut();
+            traffic.stats = CanStats::new();
+            traffic.overflow_base = bus.rx_queue.overflows();
         })
     }
+
+    /// Queues `frame` for transmission in priority order, returning
+    /// `WouldBlock` only if the software queue is full. Unlike
+    /// `nb::Can::transmit`, it never pushes out a queued frame.
+    pub fn try_transmit(&mut self, frame: &CanFrame) -> nb::Result<(), CanError> {
+        self.check_tx()?;
+        P::bus().submit(frame, false).map(|_| ())
+    }
+
+    fn check_tx(&self) -> Result<(), CanError> {
+        match self.listen_only {
+            true => Err(CanError::ListenOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Like `nb::Can::receive`, but keeps the time at which the interrupt
+    /// handler received the frame, see [`TimestampedFrame`].
+    pub fn receive_timestamped(&mut self) -> nb::Result<TimestampedFrame, CanError> {
+        let bus = P::bus();
+        if let Some(err) = bus.update_status(|status| status.last_error.take()) {
+            return Err(nb::Error::Other(err));
+        }
+        // SAFETY: only the handle owning the PIO pops, through `&mut self`.
+        match unsafe { bus.rx_queue.pop() } {
+            Some(received) => Ok(received),
+            None if !bus.is_running() => Err(nb::Error::Other(CanError::NotInitialized)),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// Chooses which frame is lost when a frame arrives with the receive queue
+    /// full. Defaults to [`OverflowPolicy::DropNewest`].
+    pub fn set_rx_overflow_policy(&mut self, policy: OverflowPolicy) {
+        P::bus().rx_queue.set_policy(policy);
+    }
+
+    /// With loopback on, every frame this node transmits is also put into the
+    /// receive queue once the bus has acknowledged it, marked as
+    /// [`TimestampedFrame::echo`], the way SocketCAN reports sent frames. Echoes
+    /// go through the acceptance filters but are not counted as received in
+    /// [`Can2040::stats`]. Off by default.
+    pub fn set_loopback(&mut self, enabled: bool) {
+        let bus = P::bus();
+        cortex_m::interrupt::free(|cs| bus.loopback.borrow(cs).set(enabled));
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        let bus = P::bus();
+        cortex_m::interrupt::free(|cs| bus.loopback.borrow(cs).get())
+    }
+
+    /// Installs `filter` in `bank`, replacing what was there. Once any bank is
+    /// in use, only frames matching at least one of them are queued.
+    ///
+    /// Panics if `bank` is not below [`FILTER_BANKS`](crate::filter::FILTER_BANKS).
+    pub fn set_filter(&mut self, bank: usize, filter: Filter) {
+        self.update_filters(|filters| filters.set(bank, Some(filter)));
+    }
+
+    pub fn clear_filter(&mut self, bank: usize) {
+        self.update_filters(|filters| filters.set(bank, None));
+    }
+
+    /// Empties every bank, so that all frames are accepted again.
+    pub fn clear_filters(&mut self) {
+        self.update_filters(|filters| *filters = FilterBanks::new());
+    }
+
+    fn update_filters(&mut self, f: impl FnOnce(&mut FilterBanks)) {
+        let bus = P::bus();
+        cortex_m::interrupt::free(|cs| {
+            let cell = bus.filters.borrow(cs);
+            let mut filters = cell.get();
+            f(&mut filters);
+            cell.set(filters);
+        })
+    }
+
+    pub(crate) fn rx_waker(&self) -> &'static WakerSlot {
+        &P::bus().rx_waker
+    }
+
+    pub(crate) fn tx_waker(&self) -> &'static WakerSlot {
+        &P::bus().tx_waker
+    }
+}
+
+impl<P: Instance> embedded_can::nb::Can for Can2040<P> {
+    type Frame = CanFrame;
+    type Error = CanError;
+
+    /// Queues `frame`. If the software queue is full, the lowest-priority
+    /// queued frame makes room if `frame` outranks it, and is returned as
+    /// `Ok(Some(_))`; otherwise this returns `WouldBlock`.
+    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Self::Error> {
+        self.check_tx()?;
+        P::bus().submit(frame, true)
+    }
+
+    fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
+        self.receive_timestamped().map(|received| received.frame)
+    }
 }
 
 // TODO(zephyr): Remove blocking::Can, if we need blocking, we can use nb::block!()
-impl embedded_can::blocking::Can for Can2040 {
+impl<P: Instance> embedded_can::blocking::Can for Can2040<P> {
     type Frame = CanFrame;
     type Error = CanError;
 
+    /// Waits for room in the transmit queue as long as queued frames keep
+    /// completing, and fails with `CanError::Timeout` once none has for
+    /// [`TX_TIMEOUT_US`].
     fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
-        unsafe {
-            if let Some(cbus) = CBUS.as_mut() {
-                let cbus_ptr = &mut *cbus as *mut _;
+        self.check_tx()?;
 
-                // Wait for CAN to be ready to transmit
-                while can2040_check_transmit(cbus_ptr) == 0 {}
-                can2040_transmit(cbus_ptr, frame as *const _ as *mut _);
+        let bus = P::bus();
+        let completed = || bus.update_status(|status| status.tx_complete);
+        let (mut since, mut seen) = (now_us(), completed());
+        loop {
+            match bus.submit(frame, false) {
+                Ok(_) => return Ok(()),
+                Err(nb::Error::Other(err)) => return Err(err),
+                Err(nb::Error::WouldBlock) => {}
+            }
+            let now = now_us();
+            if completed() != seen {
+                (since, seen) = (now, completed());
+            } else if now.wrapping_sub(since) >= TX_TIMEOUT_US {
+                return Err(CanError::Timeout);
             }
+            // No `wfi`: on a dead bus there may be no interrupt to wake up
+            // for the timeout.
+            core::hint::spin_loop();
         }
-        Ok(())
     }
 
     fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        let bus = P::bus();
         loop {
-            if let Some(received_msg) = cortex_m::interrupt::free(|cs| {
-                let mut queue = RECEIVE_QUEUE.borrow(cs).borrow_mut();
-                if !queue.is_empty() {
-                    Some(queue.remove(0))
-                } else {
-                    None
-                }
-            }) {
-                return Ok(received_msg);
+            if let Some(err) = bus.update_status(|status| status.last_error.take()) {
+                return Err(err);
+            }
+
+            // SAFETY: only the handle owning the PIO pops, through `&mut self`.
+            if let Some(received) = unsafe { bus.rx_queue.pop() } {
+                return Ok(received.frame);
+            }
+
+            if !bus.is_running() {
+                return Err(CanError::NotInitialized);
             }
 
             // Wait for interrupt (this will put the core to sleep until the next interrupt, i.e., the next message)
@@ -247,42 +903,31 @@ impl embedded_can::blocking::Can for Can2040 {
     }
 }
 
-pub const RP2040_SYS_FREQ: u32 = 125_000_000;
+impl<P: Instance> Drop for Can2040<P> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
 
-static mut CBUS: Option<can2040> = None;
+/// # Safety
+///
+/// See [`Bus::service`].
+unsafe fn irq_handler<P: Instance>() {
+    // Not inside a critical section: bit timing keeps the PIO interrupt busy,
+    // and the callback's own critical sections are short.
+    unsafe { P::bus().service() };
+}
 
+#[cfg(not(feature = "rtic"))]
 #[interrupt]
 fn PIO0_IRQ_0() {
-    unsafe {
-        if let Some(cbus) = CBUS.as_mut() {
-            can2040_pio_irq_handler(&mut *cbus as *mut _);
-        }
-    }
+    // SAFETY: this is the interrupt.
+    unsafe { irq_handler::<pac::PIO0>() };
 }
 
-/// PIO0_IRQ_0 is hard-coded by the implementation of the Can2040 C library.
-/// Therefore, if you need to enable can-bus on RP2040, you must reserve PIO0
-/// for Can2040. Additionally, when enabling Can2040, we forcibly set the
-/// priority of Can2040 to 0 to ensure that communication interrupts can
-/// receive the most real-time response.
-pub fn initialize_cbus(
-    core: &mut cortex_m::Peripherals,
-    baud_rate: u32,
-    can_rx_id: u32,
-    can_tx_id: u32,
-) -> Can2040 {
-    unsafe {
-        assert!(CBUS.is_none());
-        CBUS = Some(can2040::new());
-        let cbus = CBUS.as_mut().unwrap();
-        let cbus_ptr = &mut *cbus as *mut _;
-        can2040_setup(cbus_ptr, 0);
-        can2040_callback_config(cbus_ptr, Some(can2040_cb));
-        can2040_start(cbus_ptr, RP2040_SYS_FREQ, baud_rate, can_rx_id, can_tx_id);
-
-        // Enable interrupts and set priority for it.
-        core.NVIC.set_priority(pac::Interrupt::PIO0_IRQ_0, 0);
-        pac::NVIC::unmask(pac::Interrupt::PIO0_IRQ_0);
-        Can2040 {}
-    }
+#[cfg(not(feature = "rtic"))]
+#[interrupt]
+fn PIO1_IRQ_0() {
+    // SAFETY: this is the interrupt.
+    unsafe { irq_handler::<pac::PIO1>() };
 }
@@ -0,0 +1,74 @@
+//! Acceptance filtering in the style of bxCAN / MCP2515 ID-mask filters.
+//!
+//! can2040 hands every frame on the bus to the callback. The filter banks are
+//! checked there, before the frame is queued, so traffic the node doesn't care
+//! about never takes up space in the receive queue.
+
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+
+use crate::frame::CanFrame;
+
+/// Number of filter banks per bus.
+pub const FILTER_BANKS: usize = 8;
+
+/// One ID/mask filter. A frame matches if its identifier is of the same kind
+/// (standard or extended) and agrees with `id` in every bit set in `mask`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Filter {
+    Standard { id: u16, mask: u16 },
+    Extended { id: u32, mask: u32 },
+}
+
+impl Filter {
+    pub fn standard(id: StandardId, mask: u16) -> Self {
+        Filter::Standard { id: id.as_raw(), mask: mask & StandardId::MAX.as_raw() }
+    }
+
+    pub fn extended(id: ExtendedId, mask: u32) -> Self {
+        Filter::Extended { id: id.as_raw(), mask: mask & ExtendedId::MAX.as_raw() }
+    }
+
+    /// Matches exactly one identifier.
+    pub fn exact(id: impl Into<Id>) -> Self {
+        match id.into() {
+            Id::Standard(id) => Self::standard(id, u16::MAX),
+            Id::Extended(id) => Self::extended(id, u32::MAX),
+        }
+    }
+
+    pub fn matches(&self, frame: &CanFrame) -> bool {
+        match (*self, frame.id()) {
+            (Filter::Standard { id, mask }, Id::Standard(frame_id)) => {
+                (frame_id.as_raw() ^ id) & mask == 0
+            }
+            (Filter::Extended { id, mask }, Id::Extended(frame_id)) => {
+                (frame_id.as_raw() ^ id) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The filter banks of one bus, also usable on their own for other backends.
+/// With every bank empty all frames are accepted, otherwise a frame has to
+/// match at least one bank.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FilterBanks([Option<Filter>; FILTER_BANKS]);
+
+impl FilterBanks {
+    pub const fn new() -> Self {
+        Self([None; FILTER_BANKS])
+    }
+
+    /// Panics if `bank` is not below [`FILTER_BANKS`].
+    pub fn set(&mut self, bank: usize, filter: Option<Filter>) {
+        assert!(bank < FILTER_BANKS, "filter bank {} out of range", bank);
+        self.0[bank] = filter;
+    }
+
+    pub fn accepts(&self, frame: &CanFrame) -> bool {
+        let mut filters = self.0.iter().flatten().peekable();
+        filters.peek().is_none() || filters.any(|filter| filter.matches(frame))
+    }
+}
@@ -0,0 +1,229 @@
+//! CAN frame and error types. They only wrap the can2040 message struct, so
+//! they are also available on host builds without the driver.
+
+use core::fmt;
+
+use embedded_can::{ErrorKind, ExtendedId, Frame, Id, StandardId};
+
+use crate::can2040_lib::{can2040_msg, can2040_msg__bindgen_ty_1, CAN2040_ID_EFF, CAN2040_ID_RTR};
+
+impl can2040_msg__bindgen_ty_1 {
+    pub fn new() -> Self {
+        Self {
+            data: [0; 8], // 这里使用data字段初始化
+        }
+    }
+}
+
+impl can2040_msg {
+    /// Number of data bytes actually carried by the message. Remote frames carry
+    /// none, and DLC values 9..=15 still mean 8 bytes on classic CAN.
+    pub fn data_len(&self) -> usize {
+        if self.id & CAN2040_ID_RTR as u32 != 0 {
+            0
+        } else {
+            (self.dlc as usize).min(8)
+        }
+    }
+
+    /// The 8-byte data field, of which the first `data_len()` bytes are used.
+    pub fn data(&self) -> &[u8; 8] {
+        // SAFETY: both union fields are plain integers covering the same 8
+        // bytes, so they are always initialised and any bit pattern is valid.
+        unsafe { &self.__bindgen_anon_1.data }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CanError {
+    /// The bus has not been started on this PIO yet, or has been stopped.
+    NotInitialized,
+    /// can2040 raised `CAN2040_NOTIFY_ERROR`: the PIO receive FIFO overflowed
+    /// and at least one frame was lost.
+    Overrun,
+    /// The bus is in listen-only mode and does not transmit.
+    ListenOnly,
+    /// A blocking transmit found the transmit queue full and saw none of this
+    /// node's frames complete for [`TX_TIMEOUT_US`]: nobody acknowledges
+    /// them, e.g. because the bus is disconnected.
+    Timeout,
+}
+
+/// How long a blocking transmit waits for a transmission to complete before
+/// giving up with [`CanError::Timeout`].
+pub const TX_TIMEOUT_US: u64 = 100_000;
+
+impl embedded_can::Error for CanError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            CanError::NotInitialized => ErrorKind::Other,
+            CanError::Overrun => ErrorKind::Overrun,
+            CanError::ListenOnly => ErrorKind::Other,
+            CanError::Timeout => ErrorKind::Acknowledge,
+        }
+    }
+}
+
+impl fmt::Debug for can2040_msg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "can2040_msg(D) {{ id: {:x?}, dlc: {:x?}, data: {:x?} }}",
+            self.id,
+            self.dlc,
+            &self.data()[..self.data_len()]
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for can2040_msg {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "can2040_msg(F) {{ id: {:x}, dlc: {:x}, data: {:x} }}",
+            self.id,
+            self.dlc,
+            &self.data()[..self.data_len()]
+        )
+    }
+}
+
+/// A classic CAN frame. It has the layout of `can2040_msg`, so it can be
+/// handed to the C library as is.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(transparent)]
+pub struct CanFrame(pub(crate) can2040_msg);
+
+fn raw_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(sid) => sid.as_raw() as u32,
+        Id::Extended(eid) => eid.as_raw() | CAN2040_ID_EFF as u32,
+    }
+}
+
+impl embedded_can::Frame for CanFrame {
+    fn new(id: impl Into<embedded_can::Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+
+        let mut data_arr = [0u8; 8];
+        data_arr[..data.len()].copy_from_slice(data);
+
+        Some(CanFrame(can2040_msg {
+            id: raw_id(id.into()),
+            dlc: data.len() as u32,
+            __bindgen_anon_1: can2040_msg__bindgen_ty_1 { data: data_arr },
+        }))
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        if dlc > 8 {
+            return None;
+        }
+
+        // A remote frame carries no data; the DLC only tells the responder how
+        // many bytes are being requested.
+        Some(CanFrame(can2040_msg {
+            id: raw_id(id.into()) | CAN2040_ID_RTR as u32,
+            dlc: dlc as u32,
+            __bindgen_anon_1: can2040_msg__bindgen_ty_1::new(),
+        }))
+    }
+
+    fn is_extended(&self) -> bool {
+        self.0.id & CAN2040_ID_EFF as u32 != 0
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.0.id & CAN2040_ID_RTR as u32 != 0
+    }
+
+    fn id(&self) -> embedded_can::Id {
+        // The flag bits live above the identifier, so masking them off always
+        // leaves a valid 11 or 29-bit value.
+        if self.is_extended() {
+            Id::Extended(ExtendedId::new(self.0.id & ExtendedId::MAX.as_raw()).unwrap())
+        } else {
+            let raw = self.0.id & StandardId::MAX.as_raw() as u32;
+            Id::Standard(StandardId::new(raw as u16).unwrap())
+        }
+    }
+
+    fn dlc(&self) -> usize {
+        self.0.dlc as usize
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.0.data()[..self.0.data_len()]
+    }
+}
+
+/// Frames are equal if they would look the same on the bus: the bytes past
+/// the DLC don't count.
+impl PartialEq for CanFrame {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.id == other.0.id && self.0.dlc == other.0.dlc && self.data() == other.data()
+    }
+}
+
+impl Eq for CanFrame {}
+
+/// Formats the frame the way `cansend` takes it and `candump` prints it, e.g.
+/// `123#DEADBEEF`, `12345678#01`, `005#R4` or `005#R` for a remote frame with
+/// DLC 0.
+impl fmt::Display for CanFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id() {
+            Id::Standard(id) => write!(f, "{:03X}#", id.as_raw())?,
+            Id::Extended(id) => write!(f, "{:08X}#", id.as_raw())?,
+        }
+        if self.is_remote_frame() {
+            // can-utils only prints a DLC of 1..=8.
+            return match self.dlc() {
+                dlc @ 1..=8 => write!(f, "R{}", dlc),
+                _ => f.write_str("R"),
+            };
+        }
+        for byte in self.data() {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// A received frame and the TIMER value, in microseconds, at which the PIO
+/// interrupt finished decoding it.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimestampedFrame {
+    pub frame: CanFrame,
+    pub timestamp_us: u64,
+    /// The frame is one this node transmitted, put back into the receive
+    /// queue once it was acknowledged, see `Can2040::set_loopback`. The
+    /// timestamp is then when the transmission completed.
+    pub echo: bool,
+}
+
+impl TimestampedFrame {
+    /// Formats the frame as a line of a `candump -l` log, e.g.
+    /// `(12.345678) can0 123#DEADBEEF`, which `canplayer` and `can-utils` tools
+    /// read. The time counts from when the TIMER started, not the Unix epoch.
+    pub fn candump<'a>(&'a self, interface: &'a str) -> impl fmt::Display + 'a {
+        struct Line<'a>(&'a TimestampedFrame, &'a str);
+
+        impl fmt::Display for Line<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let Line(frame, interface) = self;
+                let (secs, micros) =
+                    (frame.timestamp_us / 1_000_000, frame.timestamp_us % 1_000_000);
+                write!(f, "({}.{:06}) {} {}", secs, micros, interface, frame.frame)
+            }
+        }
+
+        Line(self, interface)
+    }
+}
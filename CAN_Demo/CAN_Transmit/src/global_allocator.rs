@@ -1,11 +0,0 @@
-use alloc_cortex_m::CortexMHeap;
-
-#[global_allocator]
-pub static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
-
-pub fn init_allocator() {
-    // Please set the correct heap size.
-    const HEAP_SIZE: usize = 0x8000;
-    static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
-    unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP.len()) }
-}
@@ -0,0 +1,605 @@
+//! ISO-TP (ISO 15765-2) transport for payloads longer than one frame.
+//!
+//! An [`IsoTp`] channel carries payloads of up to `N` bytes between two nodes
+//! over a pair of identifiers, with normal addressing on classic CAN. A payload
+//! of up to 7 bytes goes in a single frame; longer ones are split into a first
+//! frame and consecutive frames, paced by the flow control frames the receiver
+//! sends back (block size and minimum separation time, STmin). Payloads over
+//! 4095 bytes use the 32-bit first frame length of ISO 15765-2:2016.
+//!
+//! The channel does no I/O of its own. Hand it the frames received on its
+//! `rx_id` with [`receive`](IsoTp::receive) and call [`poll`](IsoTp::poll)
+//! regularly with the time of a monotonic microsecond clock; `poll` sends
+//! whatever is due through any `embedded_can::nb::Can`, so the same code runs
+//! on `Can2040`, on the mock bus and on SocketCAN.
+
+use embedded_can::nb::Can;
+use embedded_can::{Frame, Id};
+
+use crate::frame::CanFrame;
+
+/// Default N_Bs and N_Cr: how long to wait for the peer's next frame.
+pub const DEFAULT_TIMEOUT_US: u64 = 1_000_000;
+
+const SINGLE: u8 = 0x0;
+const FIRST: u8 = 0x1;
+const CONSECUTIVE: u8 = 0x2;
+const FLOW_CONTROL: u8 = 0x3;
+
+const CONTINUE: u8 = 0x0;
+const WAIT: u8 = 0x1;
+const OVERFLOW: u8 = 0x2;
+
+/// Largest payload with a 12-bit first frame length.
+const SHORT_FIRST_MAX: usize = 0xFFF;
+
+/// How a channel sends and what it asks of its peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Identifier of the frames this channel sends, flow control included.
+    pub tx_id: Id,
+    /// Identifier of the frames the peer sends.
+    pub rx_id: Id,
+    /// Consecutive frames the peer may send before it waits for the next flow
+    /// control; 0 for no limit.
+    pub block_size: u8,
+    /// Minimum gap the peer leaves between consecutive frames. Rounded up to
+    /// what STmin can express: 100 µs steps below 1 ms, then whole ms up to
+    /// 127 ms.
+    pub st_min_us: u32,
+    /// Fill byte for frames shorter than 8 bytes, or `None` to send them short.
+    pub padding: Option<u8>,
+    /// N_Bs and N_Cr: how long to wait for flow control or the next
+    /// consecutive frame before the transfer is abandoned. Also N_As, N_Cs and
+    /// N_Ar: how long the driver may have no room for a frame that is due.
+    /// `u64::MAX` never times out.
+    pub timeout_us: u64,
+}
+
+impl Config {
+    /// No block size limit or separation time, no padding and the default
+    /// timeout.
+    pub fn new(tx_id: impl Into<Id>, rx_id: impl Into<Id>) -> Self {
+        Self {
+            tx_id: tx_id.into(),
+            rx_id: rx_id.into(),
+            block_size: 0,
+            st_min_us: 0,
+            padding: None,
+            timeout_us: DEFAULT_TIMEOUT_US,
+        }
+    }
+}
+
+/// Why a payload couldn't be sent or received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A payload is still being sent.
+    Busy,
+    /// The payload is empty or longer than the channel's buffer.
+    Length,
+    /// The peer didn't send flow control or the next consecutive frame in
+    /// time, or the driver had no room for ours.
+    Timeout,
+    /// A consecutive frame was lost or repeated.
+    Sequence,
+    /// The peer has no room for the payload.
+    Overflow,
+}
+
+/// A transfer finished, one way or the other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A payload of this many bytes arrived; read it with
+    /// [`received`](IsoTp::received).
+    Received(usize),
+    /// The payload passed to [`send`](IsoTp::send) has gone out completely.
+    Sent,
+    SendFailed(Error),
+    ReceiveFailed(Error),
+    /// A frame of this channel took the place of this one, queued earlier in
+    /// the driver with a lower priority, which won't be sent (see
+    /// `nb::Can::transmit`). Reported by the [`poll`](IsoTp::poll) after the
+    /// one that sent the frame.
+    Displaced(CanFrame),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Tx {
+    Idle,
+    /// The single or first frame is waiting for room in the driver.
+    Start,
+    FlowControl {
+        deadline_us: u64,
+    },
+    Consecutive {
+        next_us: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rx {
+    Idle,
+    Consecutive { deadline_us: u64 },
+}
+
+/// One ISO-TP channel with `N`-byte send and receive buffers. Sending and
+/// receiving are independent, so both directions can be in flight at once.
+pub struct IsoTp<const N: usize> {
+    config: Config,
+
+    tx: Tx,
+    tx_buf: [u8; N],
+    tx_len: usize,
+    tx_offset: usize,
+    tx_sn: u8,
+    /// Consecutive frames left in the current block; `None` for no limit.
+    tx_block_left: Option<u8>,
+    tx_st_min_us: u64,
+    /// Since when the driver has had no room for the frames that are due,
+    /// flow control or data.
+    tx_blocked_us: Option<u64>,
+
+    rx: Rx,
+    rx_buf: [u8; N],
+    rx_len: usize,
+    rx_offset: usize,
+    rx_sn: u8,
+    rx_block_left: u8,
+    /// Flow status to send to the peer on the next poll.
+    pending_flow: Option<u8>,
+    /// Pushed out of the driver's queue by the last frame sent.
+    displaced: Option<CanFrame>,
+}
+
+impl<const N: usize> IsoTp<N> {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            tx: Tx::Idle,
+            tx_buf: [0; N],
+            tx_len: 0,
+            tx_offset: 0,
+            tx_sn: 0,
+            tx_block_left: None,
+            tx_st_min_us: 0,
+            tx_blocked_us: None,
+            rx: Rx::Idle,
+            rx_buf: [0; N],
+            rx_len: 0,
+            rx_offset: 0,
+            rx_sn: 0,
+            rx_block_left: 0,
+            pending_flow: None,
+            displaced: None,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Starts sending `data`, which is copied; [`poll`](Self::poll) does the
+    /// rest and reports [`Event::Sent`] at the end.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.tx != Tx::Idle {
+            return Err(Error::Busy);
+        }
+        if data.is_empty() || data.len() > N {
+            return Err(Error::Length);
+        }
+        self.tx_buf[..data.len()].copy_from_slice(data);
+        self.tx_len = data.len();
+        self.forget_blocked();
+        self.tx = Tx::Start;
+        Ok(())
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.tx != Tx::Idle
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.rx != Rx::Idle
+    }
+
+    /// Abandons the payload being sent, without telling the peer.
+    pub fn cancel_send(&mut self) {
+        self.tx = Tx::Idle;
+        self.forget_blocked();
+    }
+
+    /// The last payload received. Valid until the next single or first frame
+    /// arrives.
+    pub fn received(&self) -> &[u8] {
+        &self.rx_buf[..self.rx_len.min(self.rx_offset)]
+    }
+
+    /// Handles a frame received at `now_us`. Frames with other identifiers
+    /// and malformed frames are ignored. A flow control frame the peer asked
+    /// for is sent on the next [`poll`](Self::poll).
+    pub fn receive<F: Frame>(&mut self, frame: &F, now_us: u64) -> Option<Event> {
+        if frame.is_remote_frame() || frame.id() != self.config.rx_id {
+            return None;
+        }
+        let data = frame.data();
+        let pci = *data.first()?;
+        match pci >> 4 {
+            SINGLE => {
+                let len = (pci & 0xF) as usize;
+                if len == 0 || len >= data.len() {
+                    return None;
+                }
+                // A new payload replaces one in progress.
+                self.rx = Rx::Idle;
+                if len > N {
+                    return Some(Event::ReceiveFailed(Error::Length));
+                }
+                self.rx_buf[..len].copy_from_slice(&data[1..=len]);
+                (self.rx_len, self.rx_offset) = (len, len);
+                Some(Event::Received(len))
+            }
+            FIRST => {
+                if data.len() < 8 {
+                    return None;
+                }
+                let (len, header) = match ((pci & 0xF) as usize) << 8 | data[1] as usize {
+                    0 => (u32::from_be_bytes([data[2], data[3], data[4], data[5]]) as usize, 6),
+                    len => (len, 2),
+                };
+                if len < 8 {
+                    return None;
+                }
+                self.rx = Rx::Idle;
+                if len > N {
+                    self.pending_flow = Some(OVERFLOW);
+                    return Some(Event::ReceiveFailed(Error::Length));
+                }
+                let first = &data[header..8];
+                self.rx_buf[..first.len()].copy_from_slice(first);
+                (self.rx_len, self.rx_offset, self.rx_sn) = (len, first.len(), 1);
+                self.rx_block_left = self.config.block_size;
+                self.rx = Rx::Consecutive { deadline_us: self.deadline(now_us) };
+                self.pending_flow = Some(CONTINUE);
+                None
+            }
+            CONSECUTIVE => {
+                let Rx::Consecutive { .. } = self.rx else {
+                    return None;
+                };
+                if pci & 0xF != self.rx_sn {
+                    self.rx = Rx::Idle;
+                    return Some(Event::ReceiveFailed(Error::Sequence));
+                }
+                let n = (self.rx_len - self.rx_offset).min(data.len() - 1);
+                self.rx_buf[self.rx_offset..self.rx_offset + n].copy_from_slice(&data[1..=n]);
+                self.rx_offset += n;
+                self.rx_sn = (self.rx_sn + 1) & 0xF;
+                if self.rx_offset == self.rx_len {
+                    self.rx = Rx::Idle;
+                    return Some(Event::Received(self.rx_len));
+                }
+                self.rx = Rx::Consecutive { deadline_us: self.deadline(now_us) };
+                if self.config.block_size != 0 {
+                    self.rx_block_left -= 1;
+                    if self.rx_block_left == 0 {
+                        self.rx_block_left = self.config.block_size;
+                        self.pending_flow = Some(CONTINUE);
+                    }
+                }
+                None
+            }
+            FLOW_CONTROL => {
+                let Tx::FlowControl { .. } = self.tx else {
+                    return None;
+                };
+                if data.len() < 3 {
+                    return None;
+                }
+                match pci & 0xF {
+                    CONTINUE => {
+                        self.tx_block_left = (data[1] != 0).then_some(data[1]);
+                        self.tx_st_min_us = decode_st_min(data[2]);
+                        self.tx = Tx::Consecutive { next_us: now_us };
+                        None
+                    }
+                    WAIT => {
+                        self.tx = Tx::FlowControl { deadline_us: self.deadline(now_us) };
+                        None
+                    }
+                    OVERFLOW => {
+                        self.tx = Tx::Idle;
+                        Some(Event::SendFailed(Error::Overflow))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends the flow control and data frames that are due at `now_us` and
+    /// checks the timeouts. Frames the driver has no room for are retried on
+    /// the next poll. Only errors of `can` other than `WouldBlock` are
+    /// returned as errors; failed transfers and displaced frames are reported
+    /// as events.
+    pub fn poll<C: Can>(&mut self, can: &mut C, now_us: u64) -> Result<Option<Event>, C::Error> {
+        if let Some(frame) = self.displaced.take() {
+            return Ok(Some(Event::Displaced(frame)));
+        }
+
+        // Checked first, so a flow control the driver has no room for can't
+        // hold them up. The flow control itself is given up like a data frame.
+        let blocked = self.tx_blocked_us.is_some_and(|since| now_us > self.deadline(since));
+        if let Rx::Consecutive { deadline_us } = self.rx {
+            if now_us > deadline_us || self.pending_flow.is_some() && blocked {
+                self.rx = Rx::Idle;
+                self.pending_flow = None;
+                self.forget_blocked();
+                return Ok(Some(Event::ReceiveFailed(Error::Timeout)));
+            }
+        }
+        let timed_out = match self.tx {
+            Tx::Idle => false,
+            Tx::FlowControl { deadline_us } => now_us > deadline_us,
+            Tx::Start | Tx::Consecutive { .. } => blocked,
+        };
+        if timed_out {
+            self.tx = Tx::Idle;
+            self.forget_blocked();
+            return Ok(Some(Event::SendFailed(Error::Timeout)));
+        }
+
+        if let Some(status) = self.pending_flow {
+            if blocked {
+                // Answering a first frame too long for us, which was reported
+                // when it arrived.
+                self.pending_flow = None;
+                self.forget_blocked();
+                return Ok(None);
+            }
+            let (block_size, st_min) =
+                (self.config.block_size, encode_st_min(self.config.st_min_us));
+            let flow = [FLOW_CONTROL << 4 | status, block_size, st_min];
+            if !self.transmit_due(can, &flow, now_us)? {
+                return Ok(None);
+            }
+            self.pending_flow = None;
+            if let Rx::Consecutive { .. } = self.rx {
+                self.rx = Rx::Consecutive { deadline_us: self.deadline(now_us) };
+            }
+            if self.displaced.is_some() {
+                return Ok(None);
+            }
+        }
+
+        loop {
+            match self.tx {
+                Tx::Idle | Tx::FlowControl { .. } => return Ok(None),
+                Tx::Start => return self.start(can, now_us),
+                Tx::Consecutive { next_us } => {
+                    if now_us < next_us {
+                        return Ok(None);
+                    }
+                    let n = (self.tx_len - self.tx_offset).min(7);
+                    let mut frame = [0; 8];
+                    frame[0] = CONSECUTIVE << 4 | self.tx_sn;
+                    frame[1..=n].copy_from_slice(&self.tx_buf[self.tx_offset..self.tx_offset + n]);
+                    if !self.transmit_due(can, &frame[..=n], now_us)? {
+                        return Ok(None);
+                    }
+                    self.tx_offset += n;
+                    self.tx_sn = (self.tx_sn + 1) & 0xF;
+                    if self.tx_offset == self.tx_len {
+                        self.tx = Tx::Idle;
+                        return Ok(Some(Event::Sent));
+                    }
+                    self.tx = match self.tx_block_left {
+                        Some(1) => Tx::FlowControl { deadline_us: self.deadline(now_us) },
+                        _ => Tx::Consecutive { next_us: now_us + self.tx_st_min_us },
+                    };
+                    if let Some(left) = &mut self.tx_block_left {
+                        *left -= 1;
+                    }
+                    // Report it before sending, and maybe displacing, more.
+                    if self.displaced.is_some() {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the single or first frame.
+    fn start<C: Can>(&mut self, can: &mut C, now_us: u64) -> Result<Option<Event>, C::Error> {
+        let len = self.tx_len;
+        let mut frame = [0; 8];
+        if len <= 7 {
+            frame[0] = SINGLE << 4 | len as u8;
+            frame[1..=len].copy_from_slice(&self.tx_buf[..len]);
+            if !self.transmit_due(can, &frame[..=len], now_us)? {
+                return Ok(None);
+            }
+            self.tx = Tx::Idle;
+            return Ok(Some(Event::Sent));
+        }
+
+        let header = if len <= SHORT_FIRST_MAX {
+            frame[..2].copy_from_slice(&(len as u16 | (FIRST as u16) << 12).to_be_bytes());
+            2
+        } else {
+            frame[0] = FIRST << 4;
+            frame[2..6].copy_from_slice(&(len as u32).to_be_bytes());
+            6
+        };
+        frame[header..].copy_from_slice(&self.tx_buf[..8 - header]);
+        if !self.transmit_due(can, &frame, now_us)? {
+            return Ok(None);
+        }
+        (self.tx_offset, self.tx_sn) = (8 - header, 1);
+        self.tx = Tx::FlowControl { deadline_us: self.deadline(now_us) };
+        Ok(None)
+    }
+
+    /// When something due at `now_us` times out. Saturates, so that a
+    /// `u64::MAX` timeout never expires.
+    fn deadline(&self, now_us: u64) -> u64 {
+        now_us.saturating_add(self.config.timeout_us)
+    }
+
+    /// Sends a frame that is due, noting when the driver first had no room
+    /// for it.
+    fn transmit_due<C: Can>(
+        &mut self,
+        can: &mut C,
+        data: &[u8],
+        now_us: u64,
+    ) -> Result<bool, C::Error> {
+        let sent = self.transmit(can, data)?;
+        self.tx_blocked_us = match sent {
+            true => None,
+            false => Some(self.tx_blocked_us.unwrap_or(now_us)),
+        };
+        Ok(sent)
+    }
+
+    /// Forgets when the driver first had no room, unless a frame is still
+    /// waiting for it.
+    fn forget_blocked(&mut self) {
+        if self.pending_flow.is_none() && !matches!(self.tx, Tx::Start | Tx::Consecutive { .. }) {
+            self.tx_blocked_us = None;
+        }
+    }
+
+    /// Pads and sends `data`. Returns `false` if the driver has no room.
+    fn transmit<C: Can>(&mut self, can: &mut C, data: &[u8]) -> Result<bool, C::Error> {
+        let mut frame = [self.config.padding.unwrap_or(0); 8];
+        frame[..data.len()].copy_from_slice(data);
+        let len = if self.config.padding.is_some() { 8 } else { data.len() };
+        let frame =
+            C::Frame::new(self.config.tx_id, &frame[..len]).expect("ISO-TP frames fit in 8 bytes");
+        match can.transmit(&frame) {
+            Ok(displaced) => {
+                self.displaced = displaced.and_then(|frame| match frame.is_remote_frame() {
+                    true => CanFrame::new_remote(frame.id(), frame.dlc()),
+                    false => CanFrame::new(frame.id(), frame.data()),
+                });
+                Ok(true)
+            }
+            Err(nb::Error::WouldBlock) => Ok(false),
+            Err(nb::Error::Other(err)) => Err(err),
+        }
+    }
+}
+
+/// STmin byte for a separation of at least `us`.
+fn encode_st_min(us: u32) -> u8 {
+    match us {
+        0 => 0,
+        1..=900 => 0xF0 + us.div_ceil(100) as u8,
+        _ => us.div_ceil(1000).min(0x7F) as u8,
+    }
+}
+
+/// Separation in µs asked for by an STmin byte. Reserved values mean the
+/// longest, 127 ms.
+fn decode_st_min(st_min: u8) -> u64 {
+    match st_min {
+        0x00..=0x7F => st_min as u64 * 1000,
+        0xF1..=0xF9 => (st_min - 0xF0) as u64 * 100,
+        _ => 127_000,
+    }
+}
+
+/// Blocking transfers for host tools, e.g. on [`SocketCan`](crate::socket::SocketCan).
+#[cfg(any(feature = "mock", feature = "socketcan"))]
+mod blocking {
+    use std::sync::OnceLock;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// Why a blocking transfer failed.
+    #[derive(Debug)]
+    pub enum BlockingError<E> {
+        Can(E),
+        IsoTp(Error),
+    }
+
+    impl<E> From<Error> for BlockingError<E> {
+        fn from(err: Error) -> Self {
+            BlockingError::IsoTp(err)
+        }
+    }
+
+    fn now_us() -> u64 {
+        static EPOCH: OnceLock<Instant> = OnceLock::new();
+        EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u64
+    }
+
+    impl<const N: usize> IsoTp<N> {
+        /// Sends `data` and waits until it has gone out. Frames received
+        /// meanwhile that are not flow control for this channel are dropped.
+        pub fn send_blocking<C: Can>(
+            &mut self,
+            can: &mut C,
+            data: &[u8],
+        ) -> Result<(), BlockingError<C::Error>> {
+            self.send(data)?;
+            loop {
+                match self.step(can)? {
+                    Some(Event::Sent) => return Ok(()),
+                    Some(Event::SendFailed(err)) => return Err(err.into()),
+                    _ => {}
+                }
+            }
+        }
+
+        /// Waits up to `timeout` for the next payload. Returns
+        /// `Error::Timeout` if none started arriving in that time.
+        pub fn receive_blocking<C: Can>(
+            &mut self,
+            can: &mut C,
+            timeout: Duration,
+        ) -> Result<&[u8], BlockingError<C::Error>> {
+            let start = Instant::now();
+            loop {
+                match self.step(can)? {
+                    Some(Event::Received(_)) => return Ok(self.received()),
+                    Some(Event::ReceiveFailed(err)) => return Err(err.into()),
+                    _ if !self.is_receiving() && start.elapsed() > timeout => {
+                        return Err(Error::Timeout.into())
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        /// Feeds the frames received so far, then polls, and sleeps a little if
+        /// nothing happened.
+        fn step<C: Can>(&mut self, can: &mut C) -> Result<Option<Event>, BlockingError<C::Error>> {
+            loop {
+                match can.receive() {
+                    Ok(frame) => {
+                        if let Some(event) = self.receive(&frame, now_us()) {
+                            return Ok(Some(event));
+                        }
+                    }
+                    Err(nb::Error::Other(err)) => return Err(BlockingError::Can(err)),
+                    Err(nb::Error::WouldBlock) => break,
+                }
+            }
+            let event = self.poll(can, now_us()).map_err(BlockingError::Can)?;
+            if event.is_none() {
+                thread::sleep(Duration::from_micros(100));
+            }
+            Ok(event)
+        }
+    }
+}
+
+#[cfg(any(feature = "mock", feature = "socketcan"))]
+pub use blocking::BlockingError;
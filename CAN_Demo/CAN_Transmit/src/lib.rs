@@ -1,10 +1,73 @@
 #![no_std]
 
+#[cfg(any(feature = "mock", feature = "socketcan"))]
+extern crate std;
+
+#[cfg(feature = "rp2040")]
+#[allow(warnings)]
+mod can2040_lib {
+    include!(concat!(env!("OUT_DIR"), "/can2040_lib.rs"));
+}
+
+/// Host builds don't run bindgen; mirror the part of `can2040.h` that
+/// `CanFrame` wraps.
+#[cfg(not(feature = "rp2040"))]
+#[allow(non_camel_case_types)]
+mod can2040_lib {
+    pub const CAN2040_ID_RTR: ::core::ffi::c_int = 1 << 30;
+    pub const CAN2040_ID_EFF: ::core::ffi::c_int = 1 << 31;
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct can2040_msg {
+        pub id: u32,
+        pub dlc: u32,
+        pub __bindgen_anon_1: can2040_msg__bindgen_ty_1,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub union can2040_msg__bindgen_ty_1 {
+        pub data: [u8; 8],
+        pub data32: [u32; 2],
+    }
+}
+
+mod config {
+    include!(concat!(env!("OUT_DIR"), "/config.rs"));
+}
+
+pub mod asynch;
+pub mod bitstream;
+#[cfg(feature = "rp2040")]
 pub mod core;
+pub mod filter;
+pub mod frame;
+pub mod isotp;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod ring_buffer;
+pub mod schedule;
+#[cfg(feature = "socketcan")]
+pub mod socket;
+pub mod stats;
+pub mod tx_queue;
 
+pub use asynch::AsyncCan;
+#[cfg(feature = "rp2040")]
 pub use core::*;
+pub use filter::Filter;
+pub use frame::{CanError, CanFrame, TimestampedFrame, TX_TIMEOUT_US};
+pub use isotp::IsoTp;
+pub use ring_buffer::OverflowPolicy;
+pub use schedule::Scheduler;
+pub use stats::CanStats;
 
-extern crate alloc;
-extern crate libc;
+/// Capacity of the receive queue. Set `CAN2040_RX_QUEUE_LEN` (a power of two)
+/// when building to change it; the default is 32 frames.
+pub use config::RX_QUEUE_LEN;
+/// Capacity of the software transmit queue. Set `CAN2040_TX_QUEUE_LEN` when
+/// building to change it; the default is 16 frames.
+pub use config::TX_QUEUE_LEN;
 
-pub mod global_allocator;
+extern crate libc;
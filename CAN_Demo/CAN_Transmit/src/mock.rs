@@ -0,0 +1,307 @@
+//! In-memory CAN bus for running node code on a workstation.
+//!
+//! A [`VirtualBus`] connects any number of [`MockCan`] nodes. Every frame a node
+//! transmits is recorded and delivered to all the other nodes (and to the
+//! sender itself in loopback mode). [`MockCan`] implements the same
+//! `embedded_can` and [`AsyncCan`] traits as `Can2040`, so code written against
+//! those traits runs unchanged under `cargo test`.
+//!
+//! Faults are injected per frame through [`VirtualBus::set_fault_hook`], per
+//! node with [`MockCan::set_tx_blocked`], or directly with
+//! [`VirtualBus::inject`] and [`VirtualBus::inject_error`].
+
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::asynch::{poll_nb, AsyncCan, WakerSlot};
+use crate::filter::{Filter, FilterBanks};
+use crate::frame::{CanError, CanFrame, TX_TIMEOUT_US};
+use crate::RX_QUEUE_LEN;
+
+/// What the virtual bus does with a frame, as decided by the fault hook.
+#[derive(Clone, Copy, Debug)]
+pub enum Fault {
+    /// Deliver the frame unchanged.
+    None,
+    /// Lose the frame: no node receives it.
+    Drop,
+    /// Deliver this frame instead, e.g. one with a corrupted payload.
+    Replace(CanFrame),
+    /// Deliver nothing; the next `receive` on every node returns this error.
+    Error(CanError),
+}
+
+type FaultHook = Box<dyn FnMut(&CanFrame) -> Fault + Send>;
+
+struct Node {
+    attached: bool,
+    loopback: bool,
+    tx_blocked: bool,
+    filters: FilterBanks,
+    rx: VecDeque<CanFrame>,
+    pending_error: Option<CanError>,
+    rx_overflows: u32,
+    rx_waker: Arc<WakerSlot>,
+}
+
+struct State {
+    nodes: Vec<Node>,
+    history: Vec<CanFrame>,
+    fault_hook: Option<FaultHook>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled whenever a node's queue or blocking state changes.
+    changed: Condvar,
+}
+
+/// A virtual bus shared by any number of [`MockCan`] nodes. Cloning it gives
+/// another handle to the same bus.
+#[derive(Clone)]
+pub struct VirtualBus(Arc<Shared>);
+
+impl VirtualBus {
+    pub fn new() -> Self {
+        Self(Arc::new(Shared {
+            state: Mutex::new(State { nodes: Vec::new(), history: Vec::new(), fault_hook: None }),
+            changed: Condvar::new(),
+        }))
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.state.lock().unwrap()
+    }
+
+    /// Attaches a new node to the bus.
+    pub fn node(&self) -> MockCan {
+        let rx_waker = Arc::new(WakerSlot::new());
+        let tx_waker = Arc::new(WakerSlot::new());
+        let mut state = self.state();
+        state.nodes.push(Node {
+            attached: true,
+            loopback: false,
+            tx_blocked: false,
+            filters: FilterBanks::new(),
+            rx: VecDeque::new(),
+            pending_error: None,
+            rx_overflows: 0,
+            rx_waker: rx_waker.clone(),
+        });
+        MockCan { bus: self.clone(), index: state.nodes.len() - 1, rx_waker, tx_waker }
+    }
+
+    /// Puts `frame` on the bus as if it came from a node outside the test.
+    pub fn inject(&self, frame: CanFrame) {
+        self.send(None, frame);
+    }
+
+    /// Makes the next `receive` on every node return `error`.
+    pub fn inject_error(&self, error: CanError) {
+        let mut state = self.state();
+        for node in state.nodes.iter_mut().filter(|node| node.attached) {
+            node.pending_error = Some(error);
+            node.rx_waker.wake();
+        }
+        self.0.changed.notify_all();
+    }
+
+    /// Runs `hook` on every frame before it is delivered, replacing any
+    /// previous hook.
+    pub fn set_fault_hook(&self, hook: impl FnMut(&CanFrame) -> Fault + Send + 'static) {
+        self.state().fault_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_fault_hook(&self) {
+        self.state().fault_hook = None;
+    }
+
+    /// Every frame put on the bus so far, in order and as sent, i.e. before
+    /// the fault hook ran.
+    pub fn history(&self) -> Vec<CanFrame> {
+        self.state().history.clone()
+    }
+
+    fn send(&self, sender: Option<usize>, frame: CanFrame) {
+        let mut state = self.state();
+        state.history.push(frame);
+        let fault = match state.fault_hook.as_mut() {
+            Some(hook) => hook(&frame),
+            None => Fault::None,
+        };
+
+        let frame = match fault {
+            Fault::Drop => return,
+            Fault::Replace(replacement) => replacement,
+            Fault::None | Fault::Error(_) => frame,
+        };
+        for (index, node) in state.nodes.iter_mut().enumerate() {
+            if !node.attached || (Some(index) == sender && !node.loopback) {
+                continue;
+            }
+            if let Fault::Error(error) = fault {
+                node.pending_error = Some(error);
+            } else if !node.filters.accepts(&frame) {
+                continue;
+            } else if node.rx.len() < RX_QUEUE_LEN {
+                node.rx.push_back(frame);
+            } else {
+                node.rx_overflows = node.rx_overflows.wrapping_add(1);
+            }
+            node.rx_waker.wake();
+        }
+        self.0.changed.notify_all();
+    }
+}
+
+impl Default for VirtualBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One node on a [`VirtualBus`]. Like `Can2040`, it does not receive its own
+/// frames unless loopback is enabled, and its receive queue holds
+/// [`RX_QUEUE_LEN`] frames, dropping the newest when full.
+pub struct MockCan {
+    bus: VirtualBus,
+    index: usize,
+    rx_waker: Arc<WakerSlot>,
+    tx_waker: Arc<WakerSlot>,
+}
+
+impl MockCan {
+    /// A single node on a bus of its own that receives everything it sends.
+    pub fn loopback() -> Self {
+        let mut node = VirtualBus::new().node();
+        node.set_loopback(true);
+        node
+    }
+
+    /// The bus this node is attached to, e.g. to attach more nodes or inject
+    /// faults.
+    pub fn bus(&self) -> &VirtualBus {
+        &self.bus
+    }
+
+    fn with_node<R>(&self, f: impl FnOnce(&mut Node) -> R) -> R {
+        f(&mut self.bus.state().nodes[self.index])
+    }
+
+    pub fn set_loopback(&mut self, enabled: bool) {
+        self.with_node(|node| node.loopback = enabled);
+    }
+
+    /// While set, `transmit` behaves as if can2040's transmit queue were full:
+    /// `nb` returns `WouldBlock` and `blocking`/async wait for it to clear,
+    /// `blocking` for at most [`TX_TIMEOUT_US`].
+    pub fn set_tx_blocked(&mut self, blocked: bool) {
+        self.with_node(|node| node.tx_blocked = blocked);
+        if !blocked {
+            self.tx_waker.wake();
+            self.bus.0.changed.notify_all();
+        }
+    }
+
+    /// Same as `Can2040::set_filter`.
+    pub fn set_filter(&mut self, bank: usize, filter: Filter) {
+        self.with_node(|node| node.filters.set(bank, Some(filter)));
+    }
+
+    pub fn clear_filter(&mut self, bank: usize) {
+        self.with_node(|node| node.filters.set(bank, None));
+    }
+
+    pub fn clear_filters(&mut self) {
+        self.with_node(|node| node.filters = FilterBanks::new());
+    }
+
+    /// Frames lost because this node's receive queue was full.
+    pub fn rx_overflows(&self) -> u32 {
+        self.with_node(|node| node.rx_overflows)
+    }
+}
+
+impl Drop for MockCan {
+    fn drop(&mut self) {
+        self.with_node(|node| {
+            node.attached = false;
+            node.rx.clear();
+        });
+    }
+}
+
+impl embedded_can::nb::Can for MockCan {
+    type Frame = CanFrame;
+    type Error = CanError;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Self::Error> {
+        if self.with_node(|node| node.tx_blocked) {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.bus.send(Some(self.index), *frame);
+        Ok(None)
+    }
+
+    fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
+        self.with_node(|node| {
+            if let Some(error) = node.pending_error.take() {
+                return Err(nb::Error::Other(error));
+            }
+            node.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        })
+    }
+}
+
+impl embedded_can::blocking::Can for MockCan {
+    type Frame = CanFrame;
+    type Error = CanError;
+
+    /// Waits for the node to be unblocked, and like `Can2040` gives up with
+    /// `CanError::Timeout` after [`TX_TIMEOUT_US`].
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        let timeout = Duration::from_micros(TX_TIMEOUT_US);
+        let state = self.bus.state();
+        let blocked = |state: &mut State| state.nodes[self.index].tx_blocked;
+        let (mut state, _) =
+            self.bus.0.changed.wait_timeout_while(state, timeout, blocked).unwrap();
+        if blocked(&mut state) {
+            return Err(CanError::Timeout);
+        }
+        drop(state);
+        self.bus.send(Some(self.index), *frame);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        let mut state = self.bus.state();
+        loop {
+            let node = &mut state.nodes[self.index];
+            if let Some(error) = node.pending_error.take() {
+                return Err(error);
+            }
+            if let Some(frame) = node.rx.pop_front() {
+                return Ok(frame);
+            }
+            state = self.bus.0.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl AsyncCan for MockCan {
+    type Frame = CanFrame;
+    type Error = CanError;
+
+    async fn transmit(&mut self, frame: &CanFrame) -> Result<(), CanError> {
+        let slot = self.tx_waker.clone();
+        poll_nb(&slot, || embedded_can::nb::Can::transmit(self, frame).map(|_| ())).await
+    }
+
+    async fn receive(&mut self) -> Result<CanFrame, CanError> {
+        let slot = self.rx_waker.clone();
+        poll_nb(&slot, || embedded_can::nb::Can::receive(self)).await
+    }
+}
@@ -0,0 +1,200 @@
+//! Allocation-free single-producer/single-consumer queue.
+//!
+//! The producer is the can2040 callback running inside the PIO interrupt, the
+//! consumer is whoever calls `receive`. Neither side takes a lock, and since
+//! Cortex-M0+ has no compare-and-swap everything is built from plain atomic
+//! loads and stores: the producer alone writes `tail`, `skip` and the slots,
+//! the consumer alone writes `head` and `reading`.
+//!
+//! Under `DropOldest` the producer can't take the oldest item away by moving
+//! `head`, so it moves `skip` instead, which the consumer treats as a lower
+//! bound for `head`. It may then overwrite the slot the consumer is copying
+//! out, so every slot holds two copies: `current` tells the consumer which one
+//! is the item, and the producer writes the other one if the consumer has
+//! announced it is reading the first. The consumer announces the copy in
+//! `reading` and then checks `skip` again; the producer publishes `skip` and
+//! then checks `reading`. One of them always sees the other, so either the
+//! producer keeps away from the copy or the consumer gives it up.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+/// What the producer does when it finds the queue full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OverflowPolicy {
+    /// Keep the queued items and discard the one being pushed.
+    DropNewest,
+    /// Overwrite the oldest queued item with the one being pushed.
+    DropOldest,
+}
+
+/// Fixed-capacity SPSC ring buffer. `N` must be a power of two so the free
+/// running indices can wrap without skipping slots.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    slots: UnsafeCell<[[MaybeUninit<T>; 2]; N]>,
+    /// Which copy of each slot holds its item.
+    current: [AtomicBool; N],
+    /// Next index to read.
+    head: AtomicUsize,
+    /// One past the last index written.
+    tail: AtomicUsize,
+    /// Items before this index were dropped under `DropOldest`.
+    skip: AtomicUsize,
+    /// The copy the consumer is reading, as `2 * slot + copy`, or `2 * N` if
+    /// none.
+    reading: AtomicUsize,
+    /// Items discarded under `DropNewest`.
+    dropped: AtomicU32,
+    /// Items the consumer found skipped.
+    skipped: AtomicU32,
+    drop_oldest: AtomicBool,
+}
+
+// SAFETY: items are only handed out by value, and the protocol above keeps the
+// producer from writing the copy the consumer reads. `push` and `pop` are
+// unsafe because it relies on there being one of each.
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+/// The later of two free running indices less than half the index space apart.
+fn later(a: usize, b: usize) -> usize {
+    if (b.wrapping_sub(a) as isize) > 0 {
+        b
+    } else {
+        a
+    }
+}
+
+fn add(counter: &AtomicU32, n: u32) {
+    counter.store(counter.load(Ordering::Relaxed).wrapping_add(n), Ordering::Relaxed);
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    const CAPACITY_IS_POWER_OF_TWO: () = assert!(N.is_power_of_two());
+
+    pub const fn new(policy: OverflowPolicy) -> Self {
+        let () = Self::CAPACITY_IS_POWER_OF_TWO;
+        Self {
+            slots: UnsafeCell::new([[MaybeUninit::uninit(); 2]; N]),
+            current: [const { AtomicBool::new(false) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            skip: AtomicUsize::new(0),
+            reading: AtomicUsize::new(2 * N),
+            dropped: AtomicU32::new(0),
+            skipped: AtomicU32::new(0),
+            drop_oldest: AtomicBool::new(matches!(policy, OverflowPolicy::DropOldest)),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Number of items lost to a full queue, whichever item the policy ended
+    /// up discarding.
+    pub fn overflows(&self) -> u32 {
+        let head = self.head.load(Ordering::Acquire);
+        let pending = later(head, self.skip.load(Ordering::Acquire)).wrapping_sub(head);
+        self.dropped
+            .load(Ordering::Relaxed)
+            .wrapping_add(self.skipped.load(Ordering::Relaxed))
+            .wrapping_add(pending as u32)
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        match self.drop_oldest.load(Ordering::Relaxed) {
+            false => OverflowPolicy::DropNewest,
+            true => OverflowPolicy::DropOldest,
+        }
+    }
+
+    pub fn set_policy(&self, policy: OverflowPolicy) {
+        self.drop_oldest.store(policy == OverflowPolicy::DropOldest, Ordering::Relaxed);
+    }
+
+    fn first(&self) -> usize {
+        later(self.head.load(Ordering::Acquire), self.skip.load(Ordering::SeqCst))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.first()).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `copy` of the slot of `index`, also as a value for `reading`.
+    fn copy(&self, index: usize, copy: bool) -> (*mut T, usize) {
+        let copy = 2 * (index % N) + copy as usize;
+        // SAFETY: `copy < 2 * N`, so in bounds.
+        (unsafe { self.slots.get().cast::<MaybeUninit<T>>().add(copy).cast::<T>() }, copy)
+    }
+
+    /// Producer side. Returns false if the item was discarded because the
+    /// queue was full under `DropNewest`.
+    ///
+    /// # Safety
+    ///
+    /// Only one context may push, and it must not be preempted by another push
+    /// (the interrupt handler).
+    pub unsafe fn push(&self, item: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let mut first = self.first();
+        if tail.wrapping_sub(first) >= N {
+            if self.policy() == OverflowPolicy::DropNewest {
+                add(&self.dropped, 1);
+                return false;
+            }
+            first = first.wrapping_add(1);
+        }
+        // Also catches up with `head`, so the two stay comparable.
+        self.skip.store(first, Ordering::SeqCst);
+
+        // Copy 0 unless the consumer is reading it.
+        let (_, zero) = self.copy(tail, false);
+        let copy = self.reading.load(Ordering::SeqCst) == zero;
+        // SAFETY: the slot's last item has been read or skipped, and the
+        // consumer isn't reading this copy: it publishes `reading` before
+        // checking `skip`, so it sees its item skipped if we didn't see it.
+        unsafe { self.copy(tail, copy).0.write(item) };
+        self.current[tail % N].store(copy, Ordering::Release);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Consumer side.
+    ///
+    /// # Safety
+    ///
+    /// Only one context may pop, and it must not be preempted by another pop.
+    pub unsafe fn pop(&self) -> Option<T> {
+        loop {
+            let head = self.head.load(Ordering::Relaxed);
+            let first = later(head, self.skip.load(Ordering::SeqCst));
+            if first != head {
+                self.head.store(first, Ordering::Release);
+                add(&self.skipped, first.wrapping_sub(head) as u32);
+            }
+            if first == self.tail.load(Ordering::Acquire) {
+                return None;
+            }
+
+            let (copy, reading) = self.copy(first, self.current[first % N].load(Ordering::Acquire));
+            self.reading.store(reading, Ordering::SeqCst);
+            if later(first, self.skip.load(Ordering::SeqCst)) != first {
+                // Skipped since we looked, so the copy may be being rewritten.
+                self.reading.store(2 * N, Ordering::SeqCst);
+                continue;
+            }
+            // SAFETY: published by the producer before `tail`, and it won't
+            // write the copy while `reading` names it.
+            let item = unsafe { copy.read() };
+            self.reading.store(2 * N, Ordering::SeqCst);
+            self.head.store(first.wrapping_add(1), Ordering::Release);
+            return Some(item);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Cyclic transmission of periodic frames.
+//!
+//! A [`Scheduler`] holds up to `N` messages, each with a period and a phase
+//! offset, and hands those that are due to the driver whenever it is polled
+//! with the time of a monotonic microsecond clock, such as
+//! `rp2040_hal::Timer::get_counter().ticks()`.
+
+use embedded_can::nb::Can;
+
+use crate::frame::CanFrame;
+
+/// Timing of one cyclic message. Jitter is how late, in microseconds, a
+/// frame was handed to the driver after its latest due time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JitterStats {
+    /// Frames sent on schedule. Triggered frames are not counted.
+    pub sent: u32,
+    /// Periods skipped because the scheduler was polled too late or the
+    /// driver had no room for a whole period.
+    pub missed: u32,
+    pub last_jitter_us: u32,
+    pub max_jitter_us: u32,
+    /// Sum of the jitter of all `sent` frames.
+    pub total_jitter_us: u64,
+}
+
+impl JitterStats {
+    pub fn mean_jitter_us(&self) -> u32 {
+        match self.sent {
+            0 => 0,
+            sent => (self.total_jitter_us / sent as u64) as u32,
+        }
+    }
+
+    fn record(&mut self, jitter_us: u64) {
+        let jitter_us = jitter_us.min(u32::MAX as u64) as u32;
+        self.sent = self.sent.wrapping_add(1);
+        self.last_jitter_us = jitter_us;
+        self.max_jitter_us = self.max_jitter_us.max(jitter_us);
+        self.total_jitter_us = self.total_jitter_us.wrapping_add(jitter_us as u64);
+    }
+}
+
+enum Source<'a> {
+    Frame(CanFrame),
+    /// Called each time the message is due; `None` skips that period.
+    Producer(&'a mut dyn FnMut() -> Option<CanFrame>),
+}
+
+struct Entry<'a> {
+    source: Source<'a>,
+    period_us: u64,
+    phase_us: u64,
+    /// Set on the first poll that sees the entry.
+    next_due: Option<u64>,
+    triggered: bool,
+    stats: JitterStats,
+}
+
+impl Entry<'_> {
+    /// First due time not before `now_us` on the grid `epoch + phase + k * period`.
+    fn first_due(&self, epoch_us: u64, now_us: u64) -> u64 {
+        let first = epoch_us + self.phase_us;
+        if now_us <= first {
+            return first;
+        }
+        first + (now_us - first).div_ceil(self.period_us) * self.period_us
+    }
+}
+
+/// Sends up to `N` messages at fixed periods.
+///
+/// Each message is due at `epoch + phase + k * period`, where the epoch is the
+/// time passed to the first [`poll`](Scheduler::poll). Giving messages of the
+/// same period different phases spreads them out instead of sending them in a
+/// burst. A message that can't be sent because the driver is full stays due
+/// and is retried on the next poll; once it is more than a period late, the
+/// periods it missed are skipped and counted rather than sent back to back.
+pub struct Scheduler<'a, const N: usize> {
+    entries: [Option<Entry<'a>>; N],
+    epoch_us: Option<u64>,
+}
+
+impl<'a, const N: usize> Scheduler<'a, N> {
+    pub fn new() -> Self {
+        Self { entries: core::array::from_fn(|_| None), epoch_us: None }
+    }
+
+    /// Sends `frame` every `period_us`, starting `phase_us` after the epoch.
+    /// Returns the slot of the message, or `None` if all `N` are taken.
+    ///
+    /// Panics if `period_us` is zero.
+    pub fn add(&mut self, frame: CanFrame, period_us: u64, phase_us: u64) -> Option<usize> {
+        self.insert(Source::Frame(frame), period_us, phase_us)
+    }
+
+    /// Like [`add`](Self::add), but asks `producer` for the frame each time
+    /// the message is due, e.g. to send the latest sensor reading. When it
+    /// returns `None` nothing is sent for that period.
+    pub fn add_producer(
+        &mut self,
+        producer: &'a mut dyn FnMut() -> Option<CanFrame>,
+        period_us: u64,
+        phase_us: u64,
+    ) -> Option<usize> {
+        self.insert(Source::Producer(producer), period_us, phase_us)
+    }
+
+    fn insert(&mut self, source: Source<'a>, period_us: u64, phase_us: u64) -> Option<usize> {
+        assert!(period_us > 0, "period must not be zero");
+        let slot = self.entries.iter().position(Option::is_none)?;
+        self.entries[slot] = Some(Entry {
+            source,
+            period_us,
+            phase_us,
+            next_due: None,
+            triggered: false,
+            stats: JitterStats::default(),
+        });
+        Some(slot)
+    }
+
+    pub fn remove(&mut self, slot: usize) {
+        self.entries[slot] = None;
+    }
+
+    /// Replaces what the message in `slot` sends with `frame`, keeping its
+    /// schedule and statistics.
+    ///
+    /// Panics if `slot` is empty.
+    pub fn set_frame(&mut self, slot: usize, frame: CanFrame) {
+        self.entry(slot).source = Source::Frame(frame);
+    }
+
+    /// Sends the message in `slot` on the next poll in addition to its
+    /// schedule, e.g. to answer a remote request for it.
+    ///
+    /// Panics if `slot` is empty.
+    pub fn trigger(&mut self, slot: usize) {
+        self.entry(slot).triggered = true;
+    }
+
+    pub fn stats(&self, slot: usize) -> Option<JitterStats> {
+        self.entries[slot].as_ref().map(|entry| entry.stats)
+    }
+
+    pub fn reset_stats(&mut self) {
+        for entry in self.entries.iter_mut().flatten() {
+            entry.stats = JitterStats::default();
+        }
+    }
+
+    /// The earliest time a message is due, or 0 if one is triggered. Only
+    /// known for messages the scheduler has been polled with; use it to sleep
+    /// until the next poll is needed.
+    pub fn next_due(&self) -> Option<u64> {
+        self.entries
+            .iter()
+            .flatten()
+            .filter_map(|entry| if entry.triggered { Some(0) } else { entry.next_due })
+            .min()
+    }
+
+    fn entry(&mut self, slot: usize) -> &mut Entry<'a> {
+        self.entries[slot].as_mut().expect("no message in this slot")
+    }
+
+    /// Hands every message that is due at `now_us` to `can`, in slot order,
+    /// and returns how many frames were sent. Stops at the first error other
+    /// than `WouldBlock`; the failed message stays due.
+    ///
+    /// With `Can2040` the frames go through its software transmit queue, so a
+    /// full queue may push out a lower-priority frame as with `transmit`.
+    pub fn poll<C>(&mut self, can: &mut C, now_us: u64) -> Result<usize, C::Error>
+    where
+        C: Can<Frame = CanFrame>,
+    {
+        let epoch_us = *self.epoch_us.get_or_insert(now_us);
+        let mut sent = 0;
+        for entry in self.entries.iter_mut().flatten() {
+            let due = match entry.next_due {
+                Some(due) => due,
+                None => *entry.next_due.insert(entry.first_due(epoch_us, now_us)),
+            };
+            let cyclic = now_us >= due;
+            if !cyclic && !entry.triggered {
+                continue;
+            }
+
+            let frame = match &mut entry.source {
+                Source::Frame(frame) => Some(*frame),
+                Source::Producer(producer) => producer(),
+            };
+            if let Some(frame) = &frame {
+                match can.transmit(frame) {
+                    Ok(_) => sent += 1,
+                    Err(nb::Error::WouldBlock) => continue,
+                    Err(nb::Error::Other(err)) => return Err(err),
+                }
+            }
+            entry.triggered = false;
+
+            if cyclic {
+                // The frame stands in for the latest due time; earlier ones
+                // were missed.
+                let missed = (now_us - due) / entry.period_us;
+                let due = due + missed * entry.period_us;
+                entry.stats.missed = entry.stats.missed.wrapping_add(missed as u32);
+                if frame.is_some() {
+                    entry.stats.record(now_us - due);
+                }
+                entry.next_due = Some(due + entry.period_us);
+            }
+        }
+        Ok(sent)
+    }
+}
+
+impl<const N: usize> Default for Scheduler<'_, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,107 @@
+//! SocketCAN backend, so node code written against `Can2040` runs unchanged on
+//! a Linux host.
+//!
+//! [`SocketCan`] works on any SocketCAN interface: a CANable or other USB
+//! adapter on the real bus, or a virtual interface in CI:
+//!
+//! ```shell
+//! sudo ip link add dev vcan0 type vcan && sudo ip link set up vcan0
+//! ```
+
+use std::io;
+
+use embedded_can::Frame;
+use socketcan::{CanSocket, Socket};
+
+use crate::frame::CanFrame;
+
+pub use socketcan::Error;
+
+/// A SocketCAN interface speaking in [`CanFrame`]s.
+///
+/// The `nb` and `blocking` traits switch the socket between non-blocking and
+/// blocking mode as needed, so both can be used on the same handle.
+pub struct SocketCan {
+    socket: CanSocket,
+    nonblocking: bool,
+}
+
+impl SocketCan {
+    /// Opens the interface `ifname`, e.g. `"can0"` or `"vcan0"`.
+    pub fn open(ifname: &str) -> io::Result<Self> {
+        Ok(Self { socket: CanSocket::open(ifname)?, nonblocking: false })
+    }
+
+    /// The underlying socket, e.g. to set kernel filters or timeouts.
+    pub fn socket(&self) -> &CanSocket {
+        &self.socket
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Error> {
+        if self.nonblocking != nonblocking {
+            self.socket.set_nonblocking(nonblocking)?;
+            self.nonblocking = nonblocking;
+        }
+        Ok(())
+    }
+}
+
+impl From<CanFrame> for socketcan::CanFrame {
+    fn from(frame: CanFrame) -> Self {
+        // Neither constructor can fail: a `CanFrame` never carries more than 8
+        // bytes, and the DLC clamp only matters for received 9..=15 values.
+        if frame.is_remote_frame() {
+            Frame::new_remote(frame.id(), frame.dlc().min(8)).unwrap()
+        } else {
+            Frame::new(frame.id(), frame.data()).unwrap()
+        }
+    }
+}
+
+impl TryFrom<socketcan::CanFrame> for CanFrame {
+    /// Error frames have no `CanFrame` equivalent; they are decoded instead.
+    type Error = socketcan::CanError;
+
+    fn try_from(frame: socketcan::CanFrame) -> Result<Self, Self::Error> {
+        match frame {
+            socketcan::CanFrame::Error(frame) => Err(frame.into_error()),
+            frame if frame.is_remote_frame() => {
+                Ok(Frame::new_remote(Frame::id(&frame), Frame::dlc(&frame)).unwrap())
+            }
+            frame => Ok(Frame::new(Frame::id(&frame), Frame::data(&frame)).unwrap()),
+        }
+    }
+}
+
+impl embedded_can::nb::Can for SocketCan {
+    type Frame = CanFrame;
+    type Error = Error;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Self::Error> {
+        self.set_nonblocking(true)?;
+        embedded_can::nb::Can::transmit(&mut self.socket, &(*frame).into())?;
+        Ok(None)
+    }
+
+    fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
+        self.set_nonblocking(true)?;
+        let frame = embedded_can::nb::Can::receive(&mut self.socket)?;
+        Ok(CanFrame::try_from(frame).map_err(Error::from)?)
+    }
+}
+
+impl embedded_can::blocking::Can for SocketCan {
+    type Frame = CanFrame;
+    type Error = Error;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        self.set_nonblocking(false)?;
+        embedded_can::blocking::Can::transmit(&mut self.socket, &(*frame).into())
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        self.set_nonblocking(false)?;
+        let frame = embedded_can::blocking::Can::receive(&mut self.socket)?;
+        Ok(CanFrame::try_from(frame)?)
+    }
+}
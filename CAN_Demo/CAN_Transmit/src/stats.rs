@@ -0,0 +1,115 @@
+//! Traffic counters and bus-load estimation.
+
+use embedded_can::Frame;
+
+use crate::frame::CanFrame;
+
+/// Length of the window [`BusLoad`] averages over.
+pub const LOAD_WINDOW_US: u64 = 1_000_000;
+
+const LOAD_BUCKETS: usize = 10;
+const BUCKET_US: u64 = LOAD_WINDOW_US / LOAD_BUCKETS as u64;
+
+/// Traffic counters of one bus, see `Can2040::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CanStats {
+    /// Frames received from the bus, including those rejected by the filters.
+    pub rx_frames: u32,
+    /// Data bytes in `rx_frames`.
+    pub rx_bytes: u32,
+    /// Frames this node sent and that were acknowledged.
+    pub tx_frames: u32,
+    /// Data bytes in `tx_frames`.
+    pub tx_bytes: u32,
+    /// Error notifications from can2040.
+    pub errors: u32,
+    /// Frames lost to a full receive queue.
+    pub rx_overflows: u32,
+    /// Frames rejected by the acceptance filters.
+    pub rx_filtered: u32,
+    /// Share of the bus in use over the last [`LOAD_WINDOW_US`], in tenths of
+    /// a percent (0..=1000).
+    pub bus_load: u16,
+}
+
+impl CanStats {
+    pub const fn new() -> Self {
+        Self {
+            rx_frames: 0,
+            rx_bytes: 0,
+            tx_frames: 0,
+            tx_bytes: 0,
+            errors: 0,
+            rx_overflows: 0,
+            rx_filtered: 0,
+            bus_load: 0,
+        }
+    }
+}
+
+/// Bits `frame` occupies on the bus, from start of frame to the end of the
+/// interframe space. Stuff bits depend on the content and are not counted, so
+/// this is a lower bound, typically 10-20% short.
+pub fn frame_bits(frame: &CanFrame) -> u32 {
+    // SOF, arbitration and control fields, CRC, ACK, EOF and 3 bits of IFS.
+    let overhead = if frame.is_extended() { 67 } else { 47 };
+    overhead + 8 * frame.data().len() as u32
+}
+
+/// Sliding-window count of the bits seen on the bus. The window is split in
+/// slices so old traffic ages out gradually instead of all at once.
+#[derive(Clone, Copy, Debug)]
+pub struct BusLoad {
+    bits: [u32; LOAD_BUCKETS],
+    /// Index of the newest slice, i.e. `time / BUCKET_US`.
+    slice: u64,
+}
+
+impl BusLoad {
+    pub const fn new() -> Self {
+        Self { bits: [0; LOAD_BUCKETS], slice: 0 }
+    }
+
+    /// Moves the window forward to `now_us`, clearing the slices it passed.
+    fn advance(&mut self, now_us: u64) {
+        let slice = now_us / BUCKET_US;
+        if slice < self.slice {
+            // The clock was reset (e.g. by `hal::Timer::new`); start over.
+            *self = Self { bits: [0; LOAD_BUCKETS], slice };
+            return;
+        }
+        let passed = (slice - self.slice).min(LOAD_BUCKETS as u64);
+        for i in 1..=passed {
+            self.bits[((self.slice + i) % LOAD_BUCKETS as u64) as usize] = 0;
+        }
+        self.slice = slice;
+    }
+
+    /// Accounts for a frame of `bits` that ended at `now_us`.
+    pub fn record(&mut self, now_us: u64, bits: u32) {
+        self.advance(now_us);
+        let bucket = &mut self.bits[(self.slice % LOAD_BUCKETS as u64) as usize];
+        *bucket = bucket.saturating_add(bits);
+    }
+
+    /// Bus utilisation at `bitrate` over the window ending at `now_us`, in
+    /// tenths of a percent.
+    pub fn permille(&mut self, now_us: u64, bitrate: u32) -> u16 {
+        self.advance(now_us);
+        let bits: u64 = self.bits.iter().map(|&bits| bits as u64).sum();
+        // The newest slice is only partly over.
+        let window_us = (LOAD_BUCKETS as u64 - 1) * BUCKET_US + now_us % BUCKET_US;
+        let capacity = bitrate as u64 * window_us / 1_000_000;
+        if capacity == 0 {
+            return 0;
+        }
+        (bits * 1000 / capacity).min(1000) as u16
+    }
+}
+
+impl Default for BusLoad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
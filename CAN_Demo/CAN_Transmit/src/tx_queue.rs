@@ -0,0 +1,120 @@
+//! Software transmit queue ordered by CAN arbitration priority.
+//!
+//! can2040 sends the frames in its 4-slot queue in the order they were
+//! given, so a burst of low-priority frames there would hold back a more
+//! urgent one. The driver therefore keeps at most one frame in can2040 and
+//! holds the rest here, handing over the highest-priority frame each time a
+//! transmission completes.
+
+use embedded_can::{Frame, Id};
+
+use crate::frame::CanFrame;
+
+/// Key that sorts frames the way bus arbitration does: lower wins.
+///
+/// The bits follow the order they appear on the wire: the 11-bit base ID, then
+/// RTR (standard) or SRR (extended), IDE, the 18-bit ID extension and finally
+/// RTR for extended frames. So a standard frame beats an extended one with the
+/// same base ID, and a data frame beats a remote frame with the same ID.
+pub fn arbitration_key(frame: &CanFrame) -> u32 {
+    let rtr = frame.is_remote_frame() as u32;
+    match frame.id() {
+        Id::Standard(id) => (id.as_raw() as u32) << 21 | rtr << 20,
+        Id::Extended(id) => {
+            let raw = id.as_raw();
+            (raw >> 18) << 21 | 1 << 20 | 1 << 19 | (raw & 0x3FFFF) << 1 | rtr
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    key: u32,
+    seq: u32,
+    frame: CanFrame,
+}
+
+/// Fixed-capacity priority queue of frames. Frames with equal keys leave in
+/// the order they were pushed.
+pub struct TxQueue<const N: usize> {
+    entries: [Option<Entry>; N],
+    next_seq: u32,
+}
+
+impl<const N: usize> TxQueue<N> {
+    pub const fn new() -> Self {
+        Self { entries: [None; N], next_seq: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(Option::is_none)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries = [None; N];
+    }
+
+    /// Age of `entry` in pushes, robust to `next_seq` wrapping around.
+    fn age(&self, entry: &Entry) -> u32 {
+        self.next_seq.wrapping_sub(entry.seq)
+    }
+
+    fn insert(&mut self, index: usize, frame: CanFrame) {
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        self.entries[index] = Some(Entry { key: arbitration_key(&frame), seq, frame });
+    }
+
+    /// Queues `frame`, handing it back if the queue is full.
+    pub fn push(&mut self, frame: CanFrame) -> Result<(), CanFrame> {
+        match self.entries.iter().position(Option::is_none) {
+            Some(index) => {
+                self.insert(index, frame);
+                Ok(())
+            }
+            None => Err(frame),
+        }
+    }
+
+    /// Like `push`, but when the queue is full `frame` takes the place of the
+    /// lowest-priority queued frame if it outranks it. Returns the frame that
+    /// was pushed out, or hands `frame` back if it didn't get in.
+    pub fn push_displacing(&mut self, frame: CanFrame) -> Result<Option<CanFrame>, CanFrame> {
+        let frame = match self.push(frame) {
+            Ok(()) => return Ok(None),
+            Err(frame) => frame,
+        };
+        // Lowest priority: highest key, newest among equal keys.
+        let lowest = (0..N).filter(|&i| self.entries[i].is_some()).max_by_key(|&i| {
+            let entry = self.entries[i].as_ref().unwrap();
+            (entry.key, u32::MAX - self.age(entry))
+        });
+        match lowest {
+            Some(index) if arbitration_key(&frame) < self.entries[index].unwrap().key => {
+                let displaced = self.entries[index].unwrap().frame;
+                self.insert(index, frame);
+                Ok(Some(displaced))
+            }
+            _ => Err(frame),
+        }
+    }
+
+    /// Removes the highest-priority frame.
+    pub fn pop(&mut self) -> Option<CanFrame> {
+        let index = (0..N).filter(|&i| self.entries[i].is_some()).min_by_key(|&i| {
+            let entry = self.entries[i].as_ref().unwrap();
+            (entry.key, u32::MAX - self.age(entry))
+        })?;
+        self.entries[index].take().map(|entry| entry.frame)
+    }
+}
+
+impl<const N: usize> Default for TxQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
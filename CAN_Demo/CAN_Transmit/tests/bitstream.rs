@@ -0,0 +1,251 @@
+//! Property tests of the bitstream codec: encoding and decoding agree, and the
+//! bits follow the CAN 2.0 rules. The C library only runs on the RP2040, so
+//! the comparison with can2040 uses words it produced (see `REFERENCE`), and
+//! `examples/bitstream_check.rs` repeats it on the board. They run on the host:
+//!
+//! cargo test --no-default-features --features mock --target x86_64-unknown-linux-gnu --test bitstream
+
+use can2040::bitstream::{self, DecodeError, Decoder, Sampler};
+use can2040::CanFrame;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+
+const CASES: usize = 20_000;
+
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    /// Mostly short IDs and runs of equal bits, which exercise the stuffing.
+    fn frame(&mut self) -> CanFrame {
+        let r = self.next();
+        let raw = match (r >> 8) % 3 {
+            0 => 0,
+            1 => u32::MAX,
+            _ => self.next(),
+        };
+        let id: Id = match r & 1 {
+            0 => StandardId::new((raw & 0x7FF) as u16).unwrap().into(),
+            _ => ExtendedId::new(raw & 0x1FFF_FFFF).unwrap().into(),
+        };
+        let len = (r >> 1) as usize % 9;
+        if r & 0x400 != 0 {
+            return CanFrame::new_remote(id, len).unwrap();
+        }
+        let mut data = [0u8; 8];
+        for byte in &mut data[..len] {
+            *byte = match self.next() % 4 {
+                0 => 0x00,
+                1 => 0xFF,
+                _ => self.next() as u8,
+            };
+        }
+        CanFrame::new(id, &data[..len]).unwrap()
+    }
+
+    fn frames(seed: u32) -> impl Iterator<Item = CanFrame> {
+        let mut rng = Rng(seed);
+        (0..CASES).map(move |_| rng.frame())
+    }
+}
+
+fn same(a: &CanFrame, b: &CanFrame) -> bool {
+    a.id() == b.id()
+        && a.is_remote_frame() == b.is_remote_frame()
+        && a.dlc() == b.dlc()
+        && a.data() == b.data()
+}
+
+/// Bits of the stuffed part: SOF through the CRC sequence.
+fn stuffed_len(bits: &bitstream::Bits) -> usize {
+    bits.len() - 10
+}
+
+/// Idle bus, the frames back to back with intermission, idle bus.
+fn bus(frames: &[CanFrame]) -> Vec<bool> {
+    let mut bits = vec![true; 20];
+    for frame in frames {
+        bits.extend(bitstream::encode(frame).iter());
+        bits.extend([true; 3]);
+    }
+    bits.extend([true; 20]);
+    bits
+}
+
+#[test]
+fn decode_inverts_encode() {
+    for frame in Rng::frames(1) {
+        let bits = bitstream::encode(&frame);
+        let decoded: Vec<_> = Decoder::new(bus(&[frame])).collect();
+        assert_eq!(decoded.len(), 1, "{frame}: {bits:?}");
+        let decoded = decoded[0].unwrap();
+        assert!(same(&decoded.frame, &frame), "{frame} decoded as {}", decoded.frame);
+        assert_eq!(decoded.crc, bitstream::crc15(&frame));
+        assert_eq!((decoded.start, decoded.len), (20, bits.len()));
+        assert!(!decoded.acked);
+    }
+}
+
+#[test]
+fn no_six_equal_bits_before_crc_delimiter() {
+    for frame in Rng::frames(2) {
+        let bits = bitstream::encode(&frame);
+        let stuffed: Vec<bool> = bits.iter().take(stuffed_len(&bits)).collect();
+        assert!(!stuffed.windows(6).any(|w| w.iter().all(|&b| b == w[0])), "{frame}: {bits:?}");
+        assert!(bits.iter().skip(stuffed_len(&bits)).all(|b| b), "{frame}: {bits:?}");
+    }
+}
+
+#[test]
+fn can2040_words_are_stuffed_bits_padded_recessive() {
+    for frame in Rng::frames(3) {
+        let bits = bitstream::encode(&frame);
+        let (words, used) = bitstream::can2040_words(&frame);
+        let n = stuffed_len(&bits) + 1;
+        assert_eq!(used, n.div_ceil(32));
+        for i in 0..used * 32 {
+            let bit = words[i / 32] & (1 << (31 - i % 32)) != 0;
+            assert_eq!(bit, i >= n || bits.get(i), "{frame}: bit {i}");
+        }
+        assert!(words[used..].iter().all(|&w| w == 0));
+    }
+}
+
+/// `stuffed_data` and CRC that `can2040_transmit` of `c_lib/libcan2040.a`
+/// queued for each frame, captured by running it in an ARMv6-M simulator.
+/// The IDs and data are picked for long runs of equal bits, which is where
+/// stuffing goes wrong.
+const REFERENCE: &[(&str, u16, &[u32])] = &[
+    ("123#DEADBEEF", 0x4E6B, &[0x1230_9BD5, 0xB7CE_F8E6, 0xBFFF_FFFF]),
+    ("000#", 0x0000, &[0x0410_4104, 0x10FF_FFFF]),
+    ("7FF#FFFFFFFFFFFFFFFF", 0x4C89, &[0x7DF4_47DF, 0x7DF7_DF7D, 0xF7DF_7DF7, 0xC644_FFFF]),
+    ("000#0000000000000000", 0x145B, &[0x0410_6082, 0x0820_8208, 0x2082_0820, 0x8516_FFFF]),
+    ("00000000#", 0x4610, &[0x0413_0410, 0x4106_3087]),
+    (
+        "1FFFFFFF#FFFFFFFFFFFFFFFF",
+        0x1B69,
+        &[0x7DF7_DF7D, 0xF447_DF7D, 0xF7DF_7DF7, 0xDF7D_F79B, 0x69FF_FFFF],
+    ),
+    ("12345678#010203", 0x5B84, &[0x48DC_5678, 0x2304_8282, 0x76E1_3FFF]),
+    (
+        "18FF0001#00FF00FF00FF00FF",
+        0x03D7,
+        &[0x63EF_8208, 0x2882_0BEE, 0x08FB_823E, 0xE08F_B83E, 0x5FFF_FFFF],
+    ),
+    ("100#R", 0x4BCF, &[0x1044_14BC, 0xFFFF_FFFF]),
+    ("7FF#R8", 0x20ED, &[0x7DF6_420F, 0x6FFF_FFFF]),
+    ("00000000#R8", 0x41D2, &[0x0413_0410, 0x4488_3D2F]),
+    ("18FF0001#R2", 0x6C58, &[0x63EF_8208, 0x2C2D_8B1F]),
+];
+
+/// Parses the `cansend` format `Display` produces.
+fn parse(text: &str) -> CanFrame {
+    let (id, data) = text.split_once('#').unwrap();
+    let raw = u32::from_str_radix(id, 16).unwrap();
+    let id: Id = match id.len() {
+        3 => StandardId::new(raw as u16).unwrap().into(),
+        _ => ExtendedId::new(raw).unwrap().into(),
+    };
+    match data.strip_prefix('R') {
+        Some(dlc) => CanFrame::new_remote(id, dlc.parse().unwrap_or(0)).unwrap(),
+        None => {
+            let bytes: Vec<u8> = (0..data.len() / 2)
+                .map(|i| u8::from_str_radix(&data[2 * i..2 * i + 2], 16).unwrap())
+                .collect();
+            CanFrame::new(id, &bytes).unwrap()
+        }
+    }
+}
+
+#[test]
+fn can2040_words_match_can2040() {
+    for &(text, crc, expected) in REFERENCE {
+        let frame = parse(text);
+        assert_eq!(frame.to_string(), text);
+        assert_eq!(bitstream::crc15(&frame), crc, "{text}");
+        let (words, used) = bitstream::can2040_words(&frame);
+        assert_eq!(&words[..used], expected, "{text}");
+    }
+}
+
+#[test]
+fn single_bit_errors_are_detected() {
+    for frame in Rng::frames(4).take(CASES / 10) {
+        let bits = bitstream::encode(&frame);
+        let ack_slot = stuffed_len(&bits) + 1;
+        for i in (0..bits.len()).filter(|&i| i != ack_slot) {
+            let mut corrupted = bits;
+            corrupted.toggle(i);
+            let mut stream = vec![true; 20];
+            stream.extend(corrupted.iter());
+            stream.extend([true; 20]);
+            for decoded in Decoder::new(stream).flatten() {
+                assert!(!same(&decoded.frame, &frame), "{frame}: flip {i} undetected");
+            }
+        }
+    }
+}
+
+#[test]
+fn reports_what_is_wrong() {
+    let frame = CanFrame::new(StandardId::new(0x123).unwrap(), &[0xDE, 0xAD]).unwrap();
+    let bits = bitstream::encode(&frame);
+    let decode = |bits: bitstream::Bits| {
+        let mut stream = vec![true; 11];
+        stream.extend(bits.iter());
+        Decoder::new(stream).next().unwrap()
+    };
+
+    let mut acked = bits;
+    acked.toggle(stuffed_len(&bits) + 1);
+    assert!(decode(acked).unwrap().acked);
+
+    let mut crc_delimiter = bits;
+    crc_delimiter.toggle(stuffed_len(&bits));
+    let form = DecodeError::Form { bit: stuffed_len(&bits) };
+    assert_eq!(decode(crc_delimiter).unwrap_err(), form);
+
+    // Flipping the last CRC bit leaves the stuffing of this frame intact, so
+    // only the CRC catches it.
+    let mut crc = bits;
+    crc.toggle(stuffed_len(&bits) - 1);
+    assert!(matches!(decode(crc).unwrap_err(), DecodeError::Crc { .. }));
+
+    // SOF and the first four ID bits are followed by a recessive stuff bit.
+    let zero = CanFrame::new(StandardId::ZERO, &[]).unwrap();
+    let mut stuff = bitstream::encode(&zero);
+    stuff.toggle(5);
+    assert_eq!(decode(stuff).unwrap_err(), DecodeError::Stuff { bit: 5 });
+}
+
+#[test]
+fn skips_frame_cut_off_at_start_of_capture() {
+    let frames: Vec<CanFrame> = Rng::frames(5).take(3).collect();
+    let stream = bus(&frames);
+    let decoded: Vec<_> = Decoder::new(stream[30..].iter().copied()).collect();
+    assert_eq!(decoded.len(), 2);
+    assert!(same(&decoded[0].unwrap().frame, &frames[1]));
+    assert!(same(&decoded[1].unwrap().frame, &frames[2]));
+}
+
+#[test]
+fn samples_capture_with_clock_mismatch() {
+    let frames: Vec<CanFrame> = Rng::frames(6).take(200).collect();
+    let bits = bus(&frames);
+    // 1 Msample/s analyser on a 100 kbit/s bus whose clock is 1.5% off.
+    for skew in [0.985, 1.0, 1.015] {
+        let bit_time = 10.0 * skew;
+        let samples = (0..(bits.len() as f64 * bit_time) as usize)
+            .map(|n| bits[(n as f64 / bit_time) as usize]);
+        let decoded: Vec<_> = Decoder::new(Sampler::new(samples, 1_000_000, 100_000)).collect();
+        assert_eq!(decoded.len(), frames.len(), "skew {skew}");
+        for (decoded, frame) in decoded.iter().zip(&frames) {
+            assert!(same(&decoded.unwrap().frame, frame), "skew {skew}");
+        }
+    }
+}
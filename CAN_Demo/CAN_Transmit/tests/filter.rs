@@ -0,0 +1,96 @@
+//! Acceptance filters and filter banks.
+
+use can2040::filter::{FilterBanks, FILTER_BANKS};
+use can2040::{CanFrame, Filter};
+use embedded_can::{ExtendedId, Frame, StandardId};
+
+fn sid(raw: u16) -> StandardId {
+    StandardId::new(raw).unwrap()
+}
+
+fn eid(raw: u32) -> ExtendedId {
+    ExtendedId::new(raw).unwrap()
+}
+
+fn data(id: impl Into<embedded_can::Id>) -> CanFrame {
+    CanFrame::new(id, &[1, 2]).unwrap()
+}
+
+#[test]
+fn standard_mask_ignores_cleared_bits() {
+    // 0x120..=0x12F.
+    let filter = Filter::standard(sid(0x120), 0x7F0);
+    assert!(filter.matches(&data(sid(0x120))));
+    assert!(filter.matches(&data(sid(0x12F))));
+    assert!(!filter.matches(&data(sid(0x130))));
+    assert!(!filter.matches(&data(sid(0x020))));
+
+    // Mask bits above the 11-bit identifier are dropped.
+    assert_eq!(Filter::standard(sid(0x120), 0xFFFF), Filter::exact(sid(0x120)));
+    assert!(Filter::standard(sid(0x555), 0).matches(&data(sid(0x2AA))));
+}
+
+#[test]
+fn extended_mask_ignores_cleared_bits() {
+    let filter = Filter::extended(eid(0x18FF_0000), 0x1FFF_0000);
+    assert!(filter.matches(&data(eid(0x18FF_0000))));
+    assert!(filter.matches(&data(eid(0x18FF_ABCD))));
+    assert!(!filter.matches(&data(eid(0x18FE_ABCD))));
+
+    let exact = Filter::exact(eid(0x1234_5678));
+    assert!(exact.matches(&data(eid(0x1234_5678))));
+    assert!(!exact.matches(&data(eid(0x1234_5679))));
+}
+
+#[test]
+fn standard_and_extended_never_match_each_other() {
+    // Even with every bit masked out, the kind of identifier has to agree.
+    let any_standard = Filter::standard(sid(0), 0);
+    let any_extended = Filter::extended(eid(0), 0);
+    assert!(any_standard.matches(&data(sid(0x123))));
+    assert!(!any_standard.matches(&data(eid(0x123))));
+    assert!(any_extended.matches(&data(eid(0x123))));
+    assert!(!any_extended.matches(&data(sid(0x123))));
+}
+
+#[test]
+fn remote_frames_are_filtered_by_identifier() {
+    let filter = Filter::exact(sid(0x321));
+    assert!(filter.matches(&CanFrame::new_remote(sid(0x321), 8).unwrap()));
+    assert!(!filter.matches(&CanFrame::new_remote(sid(0x322), 8).unwrap()));
+    let filter = Filter::exact(eid(0x321));
+    assert!(filter.matches(&CanFrame::new_remote(eid(0x321), 0).unwrap()));
+    assert!(!filter.matches(&CanFrame::new_remote(sid(0x321), 0).unwrap()));
+}
+
+#[test]
+fn empty_banks_accept_everything() {
+    let banks = FilterBanks::new();
+    assert!(banks.accepts(&data(sid(0x7FF))));
+    assert!(banks.accepts(&data(eid(0x1FFF_FFFF))));
+    assert!(banks.accepts(&CanFrame::new_remote(sid(0), 0).unwrap()));
+}
+
+#[test]
+fn banks_accept_a_match_in_any_bank() {
+    let mut banks = FilterBanks::new();
+    banks.set(FILTER_BANKS - 1, Some(Filter::exact(sid(0x100))));
+    assert!(banks.accepts(&data(sid(0x100))));
+    assert!(!banks.accepts(&data(sid(0x101))));
+    assert!(!banks.accepts(&data(eid(0x100))));
+
+    banks.set(2, Some(Filter::extended(eid(0x100), 0x1FFF_FF00)));
+    assert!(banks.accepts(&data(eid(0x1AB))));
+    assert!(!banks.accepts(&data(sid(0x1AB))));
+
+    // Clearing every bank opens the filter again.
+    banks.set(2, None);
+    banks.set(FILTER_BANKS - 1, None);
+    assert!(banks.accepts(&data(sid(0x101))));
+}
+
+#[test]
+#[should_panic(expected = "out of range")]
+fn bank_out_of_range() {
+    FilterBanks::new().set(FILTER_BANKS, None);
+}
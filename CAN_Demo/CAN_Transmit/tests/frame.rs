@@ -0,0 +1,108 @@
+//! Frame construction and the text formats of frames, which have to stay
+//! readable by `cansend`, `canplayer` and other can-utils tools.
+
+use can2040::{CanFrame, TimestampedFrame};
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+
+fn sid(raw: u16) -> StandardId {
+    StandardId::new(raw).unwrap()
+}
+
+fn eid(raw: u32) -> ExtendedId {
+    ExtendedId::new(raw).unwrap()
+}
+
+#[test]
+fn extended_ids_keep_all_29_bits() {
+    for raw in [0, 0x5, 0x7FF, 0x1234_5678, ExtendedId::MAX.as_raw()] {
+        let frame = CanFrame::new(eid(raw), &[1, 2]).unwrap();
+        assert!(frame.is_extended());
+        assert_eq!(frame.id(), Id::Extended(eid(raw)));
+        assert_eq!(frame.data(), &[1, 2]);
+    }
+    let frame = CanFrame::new(sid(0x7FF), &[]).unwrap();
+    assert!(!frame.is_extended());
+    assert_eq!(frame.id(), Id::Standard(sid(0x7FF)));
+}
+
+#[test]
+fn standard_and_extended_ids_of_the_same_value_differ() {
+    assert_ne!(CanFrame::new(sid(0x5), &[]), CanFrame::new(eid(0x5), &[]));
+}
+
+#[test]
+fn remote_frames_carry_a_dlc_but_no_data() {
+    let frame = CanFrame::new_remote(eid(0x1234_5678), 4).unwrap();
+    assert!(frame.is_remote_frame() && !frame.is_data_frame());
+    assert!(frame.is_extended());
+    assert_eq!((frame.dlc(), frame.data()), (4, &[][..]));
+
+    let frame = CanFrame::new_remote(sid(0x100), 0).unwrap();
+    assert!(frame.is_remote_frame() && !frame.is_extended());
+    assert_eq!((frame.dlc(), frame.data()), (0, &[][..]));
+
+    assert!(CanFrame::new_remote(sid(0x100), 9).is_none());
+    assert!(CanFrame::new(sid(0x100), &[0; 9]).is_none());
+}
+
+#[test]
+fn remote_frames_cross_the_bus() {
+    use embedded_can::blocking::Can;
+
+    let bus = can2040::mock::VirtualBus::new();
+    let (mut sender, mut receiver) = (bus.node(), bus.node());
+    let request = CanFrame::new_remote(eid(0x18FF_0001), 2).unwrap();
+    sender.transmit(&request).unwrap();
+    assert_eq!(receiver.receive().unwrap(), request);
+}
+
+#[test]
+fn remote_and_data_frames_differ() {
+    assert_ne!(CanFrame::new_remote(sid(0x5), 0), CanFrame::new(sid(0x5), &[]));
+}
+
+#[test]
+fn standard_ids_have_three_digits() {
+    assert_eq!(
+        CanFrame::new(sid(0x123), &[0xDE, 0xAD, 0xBE, 0xEF]).unwrap().to_string(),
+        "123#DEADBEEF"
+    );
+    assert_eq!(CanFrame::new(sid(0x5), &[0x0A]).unwrap().to_string(), "005#0A");
+    assert_eq!(CanFrame::new(sid(0x7FF), &[0; 8]).unwrap().to_string(), "7FF#0000000000000000");
+}
+
+#[test]
+fn extended_ids_have_eight_digits() {
+    assert_eq!(CanFrame::new(eid(0x1234_5678), &[1]).unwrap().to_string(), "12345678#01");
+    // Even when the value would fit in a standard ID.
+    assert_eq!(CanFrame::new(eid(0x5), &[]).unwrap().to_string(), "00000005#");
+}
+
+#[test]
+fn empty_data_leaves_nothing_after_the_hash() {
+    assert_eq!(CanFrame::new(sid(0x100), &[]).unwrap().to_string(), "100#");
+}
+
+#[test]
+fn remote_frames() {
+    assert_eq!(CanFrame::new_remote(sid(0x5), 4).unwrap().to_string(), "005#R4");
+    assert_eq!(CanFrame::new_remote(sid(0x5), 8).unwrap().to_string(), "005#R8");
+    assert_eq!(CanFrame::new_remote(sid(0x5), 0).unwrap().to_string(), "005#R");
+    assert_eq!(CanFrame::new_remote(eid(0x18FF_0001), 2).unwrap().to_string(), "18FF0001#R2");
+}
+
+#[test]
+fn candump_log_lines() {
+    let line = |frame, timestamp_us| {
+        TimestampedFrame { frame, timestamp_us, echo: false }.candump("can0").to_string()
+    };
+    assert_eq!(
+        line(CanFrame::new(sid(0x123), &[0xDE, 0xAD]).unwrap(), 12_345_678),
+        "(12.345678) can0 123#DEAD"
+    );
+    assert_eq!(line(CanFrame::new(eid(0x1), &[]).unwrap(), 0), "(0.000000) can0 00000001#");
+    assert_eq!(
+        line(CanFrame::new_remote(sid(0x7FF), 0).unwrap(), 1_000_000_001),
+        "(1000.000001) can0 7FF#R"
+    );
+}
@@ -0,0 +1,364 @@
+//! ISO-TP between nodes of the mock bus, stepped with a simulated clock.
+
+use std::thread;
+use std::time::Duration;
+
+use can2040::isotp::{BlockingError, Config, Error, Event, IsoTp};
+use can2040::mock::{Fault, MockCan, VirtualBus};
+use can2040::{CanError, CanFrame};
+use embedded_can::nb::Can;
+use embedded_can::{Frame, StandardId};
+
+const STEP_US: u64 = 100;
+
+fn id(raw: u16) -> StandardId {
+    StandardId::new(raw).unwrap()
+}
+
+/// A channel from 0x7E0 to 0x7E8, and its peer.
+fn configs() -> (Config, Config) {
+    (Config::new(id(0x7E0), id(0x7E8)), Config::new(id(0x7E8), id(0x7E0)))
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+struct Node<const N: usize> {
+    can: MockCan,
+    isotp: IsoTp<N>,
+    events: Vec<(u64, Event)>,
+}
+
+impl<const N: usize> Node<N> {
+    fn new(bus: &VirtualBus, config: Config) -> Self {
+        Self { can: bus.node(), isotp: IsoTp::new(config), events: Vec::new() }
+    }
+
+    fn step(&mut self, now_us: u64) {
+        while let Ok(frame) = self.can.receive() {
+            if let Some(event) = self.isotp.receive(&frame, now_us) {
+                self.events.push((now_us, event));
+            }
+        }
+        if let Some(event) = self.isotp.poll(&mut self.can, now_us).unwrap() {
+            self.events.push((now_us, event));
+        }
+    }
+}
+
+/// Steps both nodes until `done` or `limit_us` of simulated time; returns the time.
+fn run<const A: usize, const B: usize>(
+    a: &mut Node<A>,
+    b: &mut Node<B>,
+    limit_us: u64,
+    done: impl Fn(&Node<A>, &Node<B>) -> bool,
+) -> u64 {
+    let mut now = 0;
+    while !done(a, b) && now < limit_us {
+        a.step(now);
+        b.step(now);
+        now += STEP_US;
+    }
+    now
+}
+
+fn pci_types(history: &[CanFrame]) -> String {
+    history.iter().map(|f| char::from(b"SFCW"[(f.data()[0] >> 4) as usize])).collect()
+}
+
+#[test]
+fn single_frame_with_padding() {
+    let bus = VirtualBus::new();
+    let (mut tx, rx) = configs();
+    tx.padding = Some(0xCC);
+    let (mut a, mut b) = (Node::<64>::new(&bus, tx), Node::<64>::new(&bus, rx));
+
+    a.isotp.send(b"hello").unwrap();
+    run(&mut a, &mut b, 10_000, |_, b| !b.events.is_empty());
+    assert_eq!(a.events, [(0, Event::Sent)]);
+    assert_eq!(b.events, [(0, Event::Received(5))]);
+    assert_eq!(b.isotp.received(), b"hello");
+    assert_eq!(bus.history()[0].data(), [0x05, b'h', b'e', b'l', b'l', b'o', 0xCC, 0xCC]);
+}
+
+#[test]
+fn segmented_with_block_size_and_st_min() {
+    let bus = VirtualBus::new();
+    let (tx, mut rx) = configs();
+    rx.block_size = 4;
+    rx.st_min_us = 2_000;
+    let (mut a, mut b) = (Node::<256>::new(&bus, tx), Node::<256>::new(&bus, rx));
+
+    let data = payload(100);
+    a.isotp.send(&data).unwrap();
+    run(&mut a, &mut b, 1_000_000, |_, b| !b.events.is_empty());
+    assert_eq!(b.events.len(), 1);
+    assert_eq!(b.events[0].1, Event::Received(100));
+    assert_eq!(b.isotp.received(), data);
+    assert_eq!(a.events.last().unwrap().1, Event::Sent);
+
+    // 6 bytes in the first frame, then 14 consecutive frames in blocks of 4.
+    let history = bus.history();
+    assert_eq!(pci_types(&history), "FWCCCCWCCCCWCCCCWCC");
+    assert_eq!(history[0].data()[..2], [0x10, 100]);
+    assert_eq!(history[1].data(), [0x30, 4, 2]);
+    assert_eq!(history[2].data()[0], 0x21);
+    // Consecutive frames at least STmin apart within a block; the first of a
+    // block follows its flow control right away.
+    assert!(b.events[0].0 >= 10 * 2_000);
+}
+
+#[test]
+fn payload_over_4095_bytes() {
+    let bus = VirtualBus::new();
+    let (tx, mut rx) = configs();
+    // Without a block size the sender would overrun the receive queue.
+    rx.block_size = 16;
+    let (mut a, mut b) = (Node::<5000>::new(&bus, tx), Node::<5000>::new(&bus, rx));
+
+    let data = payload(5000);
+    a.isotp.send(&data).unwrap();
+    run(&mut a, &mut b, 10_000_000, |_, b| !b.events.is_empty());
+    assert_eq!(b.events[0].1, Event::Received(5000));
+    assert_eq!(b.isotp.received(), data);
+    assert_eq!(bus.history()[0].data(), [0x10, 0, 0, 0, 0x13, 0x88, data[0], data[1]]);
+    // Sequence numbers wrap from 0xF to 0, and a block ends after 16 frames.
+    let pci: Vec<u8> = bus.history()[15..19].iter().map(|f| f.data()[0]).collect();
+    assert_eq!(pci, [0x2E, 0x2F, 0x20, 0x30]);
+}
+
+#[test]
+fn both_directions_at_once() {
+    let bus = VirtualBus::new();
+    let (tx, rx) = configs();
+    let (mut a, mut b) = (Node::<64>::new(&bus, tx), Node::<64>::new(&bus, rx));
+
+    a.isotp.send(&payload(40)).unwrap();
+    b.isotp.send(&payload(30)).unwrap();
+    run(&mut a, &mut b, 1_000_000, |a, b| a.events.len() == 2 && b.events.len() == 2);
+    assert!(a.events.iter().any(|e| e.1 == Event::Received(30)));
+    assert!(b.events.iter().any(|e| e.1 == Event::Received(40)));
+    assert_eq!(a.isotp.received(), payload(30));
+    assert_eq!(b.isotp.received(), payload(40));
+}
+
+#[test]
+fn send_times_out_without_flow_control() {
+    let bus = VirtualBus::new();
+    let (mut tx, _) = configs();
+    tx.timeout_us = 50_000;
+    let mut a = Node::<64>::new(&bus, tx);
+    let mut quiet = Node::<64>::new(&bus, Config::new(id(0x100), id(0x101)));
+
+    a.isotp.send(&payload(20)).unwrap();
+    assert_eq!(a.isotp.send(b"x"), Err(Error::Busy));
+    run(&mut a, &mut quiet, 1_000_000, |a, _| !a.events.is_empty());
+    let (at, event) = a.events[0];
+    assert_eq!(event, Event::SendFailed(Error::Timeout));
+    assert!((50_000..=50_000 + 2 * STEP_US).contains(&at));
+    assert!(!a.isotp.is_sending());
+}
+
+#[test]
+fn send_times_out_while_the_driver_has_no_room() {
+    let bus = VirtualBus::new();
+    let (mut tx, rx) = configs();
+    tx.timeout_us = 50_000;
+
+    // The first frame never gets out.
+    let mut a = Node::<64>::new(&bus, tx);
+    let mut b = Node::<64>::new(&bus, rx);
+    a.can.set_tx_blocked(true);
+    a.isotp.send(&payload(20)).unwrap();
+    run(&mut a, &mut b, 1_000_000, |a, _| !a.events.is_empty());
+    let (at, event) = a.events[0];
+    assert_eq!(event, Event::SendFailed(Error::Timeout));
+    assert!((50_000..=50_000 + 2 * STEP_US).contains(&at));
+    assert!(bus.history().is_empty());
+
+    // Consecutive frames stop getting out after the flow control.
+    let mut a = Node::<64>::new(&bus, tx);
+    a.isotp.send(&payload(20)).unwrap();
+    run(&mut a, &mut b, 1_000_000, |_, _| bus.history().len() == 2);
+    a.can.set_tx_blocked(true);
+    run(&mut a, &mut b, 1_000_000, |a, _| !a.events.is_empty());
+    let (at, event) = a.events[0];
+    assert_eq!(event, Event::SendFailed(Error::Timeout));
+    assert!((50_000..=50_000 + 2 * STEP_US).contains(&at));
+    assert_eq!(pci_types(&bus.history()), "FW");
+}
+
+#[test]
+fn receive_times_out_while_flow_control_has_no_room() {
+    let bus = VirtualBus::new();
+    let (tx, mut rx) = configs();
+    rx.timeout_us = 20_000;
+    let (mut a, mut b) = (Node::<64>::new(&bus, tx), Node::<64>::new(&bus, rx));
+
+    b.can.set_tx_blocked(true);
+    a.isotp.send(&payload(20)).unwrap();
+    run(&mut a, &mut b, 1_000_000, |_, b| !b.events.is_empty());
+    let (at, event) = b.events[0];
+    assert_eq!(event, Event::ReceiveFailed(Error::Timeout));
+    assert!((20_000..=20_000 + 2 * STEP_US).contains(&at));
+    assert_eq!(pci_types(&bus.history()), "F");
+}
+
+#[test]
+fn longest_timeout_never_expires() {
+    let bus = VirtualBus::new();
+    let (mut tx, mut rx) = configs();
+    (tx.timeout_us, rx.timeout_us) = (u64::MAX, u64::MAX);
+    let mut a = Node::<64>::new(&bus, tx);
+
+    // Neither waiting for flow control nor for room in the driver gives up.
+    let mut quiet = Node::<64>::new(&bus, Config::new(id(0x100), id(0x101)));
+    a.isotp.send(&payload(20)).unwrap();
+    run(&mut a, &mut quiet, 1_000_000, |_, _| false);
+    a.isotp.cancel_send();
+    a.can.set_tx_blocked(true);
+    a.isotp.send(&payload(20)).unwrap();
+    run(&mut a, &mut quiet, 1_000_000, |_, _| false);
+    assert!(a.events.is_empty() && a.isotp.is_sending());
+
+    let mut b = Node::<64>::new(&bus, rx);
+    a.can.set_tx_blocked(false);
+    run(&mut a, &mut b, 1_000_000, |a, b| !a.events.is_empty() && !b.events.is_empty());
+    assert_eq!(a.events[0].1, Event::Sent);
+    assert_eq!(b.events[0].1, Event::Received(20));
+}
+
+#[test]
+fn receive_times_out_when_the_sender_stops() {
+    let bus = VirtualBus::new();
+    let (tx, mut rx) = configs();
+    rx.timeout_us = 20_000;
+    let (mut a, mut b) = (Node::<64>::new(&bus, tx), Node::<64>::new(&bus, rx));
+
+    // Lose every consecutive frame.
+    bus.set_fault_hook(|f| if f.data()[0] >> 4 == 2 { Fault::Drop } else { Fault::None });
+    a.isotp.send(&payload(20)).unwrap();
+    run(&mut a, &mut b, 1_000_000, |_, b| !b.events.is_empty());
+    assert_eq!(b.events[0].1, Event::ReceiveFailed(Error::Timeout));
+}
+
+#[test]
+fn lost_consecutive_frame_is_a_sequence_error() {
+    let bus = VirtualBus::new();
+    let (tx, rx) = configs();
+    let (mut a, mut b) = (Node::<64>::new(&bus, tx), Node::<64>::new(&bus, rx));
+
+    bus.set_fault_hook(|f| if f.data()[0] == 0x22 { Fault::Drop } else { Fault::None });
+    a.isotp.send(&payload(40)).unwrap();
+    run(&mut a, &mut b, 1_000_000, |a, b| !a.events.is_empty() && !b.events.is_empty());
+    assert_eq!(b.events[0].1, Event::ReceiveFailed(Error::Sequence));
+    assert_eq!(a.events[0].1, Event::Sent);
+}
+
+#[test]
+fn receiver_without_room_answers_overflow() {
+    let bus = VirtualBus::new();
+    let (tx, rx) = configs();
+    let (mut a, mut b) = (Node::<256>::new(&bus, tx), Node::<16>::new(&bus, rx));
+
+    a.isotp.send(&payload(100)).unwrap();
+    run(&mut a, &mut b, 1_000_000, |a, _| !a.events.is_empty());
+    assert_eq!(a.events[0].1, Event::SendFailed(Error::Overflow));
+    assert_eq!(b.events[0].1, Event::ReceiveFailed(Error::Length));
+    assert_eq!(bus.history()[1].data(), [0x32, 0, 0]);
+    assert_eq!(Node::<16>::new(&bus, configs().0).isotp.send(&payload(17)), Err(Error::Length));
+}
+
+#[test]
+fn overflow_answer_is_given_up_while_the_driver_has_no_room() {
+    let bus = VirtualBus::new();
+    let (mut tx, mut rx) = configs();
+    (tx.timeout_us, rx.timeout_us) = (50_000, 20_000);
+    let (mut a, mut b) = (Node::<256>::new(&bus, tx), Node::<16>::new(&bus, rx));
+
+    b.can.set_tx_blocked(true);
+    a.isotp.send(&payload(100)).unwrap();
+    run(&mut a, &mut b, 20_000 + 2 * STEP_US, |_, _| false);
+    assert_eq!(b.events[0].1, Event::ReceiveFailed(Error::Length));
+
+    // Too late to answer once the driver has room again.
+    b.can.set_tx_blocked(false);
+    run(&mut a, &mut b, 1_000_000, |a, _| !a.events.is_empty());
+    assert_eq!(a.events[0].1, Event::SendFailed(Error::Timeout));
+    assert_eq!(pci_types(&bus.history()), "F");
+}
+
+#[test]
+fn ignores_other_traffic() {
+    let bus = VirtualBus::new();
+    let (_, rx) = configs();
+    let mut b = Node::<64>::new(&bus, rx);
+    let mut other = bus.node();
+
+    other.transmit(&CanFrame::new(id(0x123), &[0x03, 1, 2, 3]).unwrap()).unwrap();
+    other.transmit(&CanFrame::new_remote(id(0x7E0), 8).unwrap()).unwrap();
+    // Consecutive frame without a first frame, and a single frame longer than its data.
+    other.transmit(&CanFrame::new(id(0x7E0), &[0x21, 1, 2]).unwrap()).unwrap();
+    other.transmit(&CanFrame::new(id(0x7E0), &[0x05, 1, 2]).unwrap()).unwrap();
+    b.step(0);
+    assert!(b.events.is_empty());
+}
+
+/// A driver whose full queue holds one lower-priority frame, which the next
+/// transmit pushes out.
+struct Displacing(Option<CanFrame>);
+
+impl Can for Displacing {
+    type Frame = CanFrame;
+    type Error = CanError;
+
+    fn transmit(&mut self, _: &CanFrame) -> nb::Result<Option<CanFrame>, CanError> {
+        Ok(self.0.take())
+    }
+
+    fn receive(&mut self) -> nb::Result<CanFrame, CanError> {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+#[test]
+fn reports_the_frame_it_displaced() {
+    let queued = CanFrame::new(id(0x7FF), &[1, 2]).unwrap();
+    let mut can = Displacing(Some(queued));
+    let mut isotp = IsoTp::<64>::new(configs().0);
+
+    isotp.send(b"hi").unwrap();
+    assert_eq!(isotp.poll(&mut can, 0), Ok(Some(Event::Sent)));
+    assert_eq!(isotp.poll(&mut can, STEP_US), Ok(Some(Event::Displaced(queued))));
+    assert_eq!(isotp.poll(&mut can, 2 * STEP_US), Ok(None));
+}
+
+#[test]
+fn blocking_transfer_between_threads() {
+    let bus = VirtualBus::new();
+    let (tx, mut rx) = configs();
+    rx.block_size = 8;
+    let mut sender = bus.node();
+    let mut receiver = bus.node();
+
+    let data = payload(1000);
+    let expected = data.clone();
+    let handle = thread::spawn(move || {
+        let mut isotp = IsoTp::<1024>::new(rx);
+        isotp.receive_blocking(&mut receiver, Duration::from_secs(5)).map(|d| d.to_vec())
+    });
+    IsoTp::<1024>::new(tx).send_blocking(&mut sender, &data).unwrap();
+    assert_eq!(handle.join().unwrap().unwrap(), expected);
+
+    let mut isotp = IsoTp::<16>::new(configs().1);
+    let nothing = isotp.receive_blocking(&mut bus.node(), Duration::from_millis(10));
+    assert!(matches!(nothing, Err(BlockingError::IsoTp(Error::Timeout))));
+
+    let mut stalled = bus.node();
+    stalled.set_tx_blocked(true);
+    let mut config = configs().0;
+    config.timeout_us = 10_000;
+    let stuck = IsoTp::<16>::new(config).send_blocking(&mut stalled, b"hi");
+    assert!(matches!(stuck, Err(BlockingError::IsoTp(Error::Timeout))));
+}
@@ -0,0 +1,136 @@
+//! Host tests of the parts of the driver that don't need the RP2040: frames,
+//! the receive queue and the mock bus. They are small enough to run under
+//! Miri, which checks the remaining `unsafe` and the cross-thread accesses:
+//!
+//! cargo +nightly miri test --no-default-features --features mock --target x86_64-unknown-linux-gnu --test mock
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use can2040::mock::{Fault, VirtualBus};
+use can2040::ring_buffer::RingBuffer;
+use can2040::{CanError, CanFrame, OverflowPolicy, RX_QUEUE_LEN, TX_TIMEOUT_US};
+use embedded_can::{Frame, StandardId};
+
+const ITEMS: u32 = if cfg!(miri) { 200 } else { 100_000 };
+
+fn frame(id: u16, data: &[u8]) -> CanFrame {
+    CanFrame::new(StandardId::new(id).unwrap(), data).unwrap()
+}
+
+#[test]
+fn frame_fields_round_trip() {
+    let data = frame(0x123, &[0xDE, 0xAD, 0xBE, 0xEF]);
+    assert_eq!(data.data(), &[0xDE, 0xAD, 0xBE, 0xEF]);
+    assert_eq!(data.to_string(), "123#DEADBEEF");
+    assert!(format!("{data:?}").contains("[de, ad, be, ef]"));
+}
+
+/// The consumer keeps reading while the producer laps it, so every slot is
+/// contended. Items must come out whole and in order.
+#[test]
+fn ring_buffer_drop_oldest_under_contention() {
+    let queue = Arc::new(RingBuffer::<(u32, u32), 8>::new(OverflowPolicy::DropOldest));
+    let producer = {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            for i in 0..ITEMS {
+                // SAFETY: this thread is the only producer.
+                unsafe { queue.push((i, !i)) };
+            }
+        })
+    };
+
+    let mut last = None;
+    let mut received = 0;
+    let mut pop = || {
+        // SAFETY: this thread is the only consumer.
+        let item = unsafe { queue.pop() };
+        if let Some((i, check)) = item {
+            assert_eq!(check, !i, "torn item");
+            assert!(last < Some(i), "out of order");
+            last = Some(i);
+            received += 1;
+        }
+        item.is_some()
+    };
+    while !producer.is_finished() {
+        pop();
+    }
+    // `is_finished` doesn't make the producer's last pushes visible, `join` does.
+    producer.join().unwrap();
+    while pop() {}
+    assert_eq!(last, Some(ITEMS - 1));
+    assert_eq!(received + queue.overflows(), ITEMS);
+}
+
+#[test]
+fn ring_buffer_drop_newest_keeps_oldest() {
+    let queue = RingBuffer::<u32, 4>::new(OverflowPolicy::DropNewest);
+    // SAFETY: one thread both pushes and pops, never at the same time.
+    unsafe {
+        for i in 0..6 {
+            assert_eq!(queue.push(i), i < 4);
+        }
+        assert_eq!(queue.overflows(), 2);
+        assert_eq!((0..4).map(|_| queue.pop().unwrap()).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        assert_eq!(queue.pop(), None);
+    }
+}
+
+#[test]
+fn nodes_exchange_frames_across_threads() {
+    use embedded_can::blocking::Can;
+
+    let bus = VirtualBus::new();
+    bus.set_fault_hook(|frame| match frame.data() {
+        [0] => Fault::Drop,
+        _ => Fault::None,
+    });
+    let mut sender = bus.node();
+    let mut receiver = bus.node();
+    // Fits in the receive queue, which drops frames the receiver is too slow for.
+    let count = RX_QUEUE_LEN.min(30);
+
+    let handle = thread::spawn(move || {
+        for i in 0..count {
+            sender.transmit(&frame(0x100, &[i as u8])).unwrap();
+        }
+    });
+    // Frame 0 is dropped by the fault hook.
+    for i in 1..count {
+        assert_eq!(receiver.receive().unwrap().data(), &[i as u8]);
+    }
+    handle.join().unwrap();
+    assert_eq!(bus.history().len(), count);
+}
+
+#[test]
+fn blocking_transmit_times_out_on_a_stalled_bus() {
+    use embedded_can::blocking::Can;
+
+    let bus = VirtualBus::new();
+    let mut node = bus.node();
+    node.set_tx_blocked(true);
+    let start = Instant::now();
+    assert_eq!(node.transmit(&frame(0x100, &[1])), Err(CanError::Timeout));
+    assert!(start.elapsed() >= Duration::from_micros(TX_TIMEOUT_US));
+    assert!(bus.history().is_empty());
+
+    node.set_tx_blocked(false);
+    node.transmit(&frame(0x100, &[2])).unwrap();
+    assert_eq!(bus.history().len(), 1);
+}
+
+#[test]
+fn errors_are_reported_once_ahead_of_frames() {
+    use embedded_can::blocking::Can;
+
+    let bus = VirtualBus::new();
+    let mut node = bus.node();
+    bus.inject(frame(0x100, &[1]));
+    bus.inject_error(CanError::Overrun);
+    assert_eq!(node.receive(), Err(CanError::Overrun));
+    assert_eq!(node.receive().unwrap().data(), &[1]);
+}
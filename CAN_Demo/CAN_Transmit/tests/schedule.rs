@@ -0,0 +1,149 @@
+//! Cyclic transmission on the mock bus, polled with a simulated clock.
+
+use std::cell::Cell;
+
+use can2040::mock::VirtualBus;
+use can2040::schedule::JitterStats;
+use can2040::{CanFrame, Scheduler};
+use embedded_can::{Frame, Id, StandardId};
+
+fn frame(id: u16) -> CanFrame {
+    CanFrame::new(StandardId::new(id).unwrap(), &[id as u8]).unwrap()
+}
+
+/// Identifiers of the frames on the bus so far.
+fn ids(bus: &VirtualBus) -> Vec<u16> {
+    bus.history()
+        .iter()
+        .map(|f| match f.id() {
+            Id::Standard(id) => id.as_raw(),
+            Id::Extended(_) => unreachable!(),
+        })
+        .collect()
+}
+
+#[test]
+fn messages_are_due_on_their_phase_and_period() {
+    let bus = VirtualBus::new();
+    let mut can = bus.node();
+    let mut scheduler = Scheduler::<2>::new();
+    scheduler.add(frame(0x100), 10_000, 0).unwrap();
+    scheduler.add(frame(0x200), 10_000, 5_000).unwrap();
+
+    // The first poll sets the epoch.
+    assert_eq!(scheduler.poll(&mut can, 1_000_000).unwrap(), 1);
+    assert_eq!(scheduler.next_due(), Some(1_005_000));
+    assert_eq!(scheduler.poll(&mut can, 1_004_999).unwrap(), 0);
+    for now in (1_005_000..1_030_000).step_by(1_000) {
+        scheduler.poll(&mut can, now).unwrap();
+    }
+    assert_eq!(ids(&bus), [0x100, 0x200, 0x100, 0x200, 0x100, 0x200]);
+    assert_eq!(scheduler.next_due(), Some(1_030_000));
+
+    let on_time = scheduler.stats(0).unwrap();
+    assert_eq!((on_time.sent, on_time.missed, on_time.max_jitter_us), (3, 0, 0));
+    assert_eq!(scheduler.stats(1).unwrap().sent, 3);
+}
+
+#[test]
+fn late_polls_skip_missed_periods() {
+    let bus = VirtualBus::new();
+    let mut can = bus.node();
+    let mut scheduler = Scheduler::<1>::new();
+    let slot = scheduler.add(frame(0x100), 10_000, 0).unwrap();
+
+    scheduler.poll(&mut can, 0).unwrap();
+    // Due at 10 000, 20 000 and 30 000: one frame for the latest, two missed.
+    assert_eq!(scheduler.poll(&mut can, 35_000).unwrap(), 1);
+    assert_eq!(scheduler.next_due(), Some(40_000));
+    scheduler.poll(&mut can, 41_000).unwrap();
+    assert_eq!(bus.history().len(), 3);
+
+    let stats = scheduler.stats(slot).unwrap();
+    assert_eq!(
+        stats,
+        JitterStats {
+            sent: 3,
+            missed: 2,
+            last_jitter_us: 1_000,
+            max_jitter_us: 5_000,
+            total_jitter_us: 6_000,
+        }
+    );
+    assert_eq!(stats.mean_jitter_us(), 2_000);
+
+    scheduler.reset_stats();
+    assert_eq!(scheduler.stats(slot), Some(JitterStats::default()));
+}
+
+#[test]
+fn blocked_driver_keeps_the_message_due() {
+    let bus = VirtualBus::new();
+    let mut can = bus.node();
+    let mut scheduler = Scheduler::<1>::new();
+    scheduler.add(frame(0x100), 10_000, 0).unwrap();
+
+    can.set_tx_blocked(true);
+    assert_eq!(scheduler.poll(&mut can, 0).unwrap(), 0);
+    assert_eq!(scheduler.poll(&mut can, 3_000).unwrap(), 0);
+    assert_eq!(scheduler.next_due(), Some(0));
+    can.set_tx_blocked(false);
+    assert_eq!(scheduler.poll(&mut can, 4_000).unwrap(), 1);
+
+    let stats = scheduler.stats(0).unwrap();
+    assert_eq!((stats.sent, stats.missed, stats.last_jitter_us), (1, 0, 4_000));
+    assert_eq!(scheduler.next_due(), Some(10_000));
+}
+
+#[test]
+fn trigger_sends_once_without_moving_the_schedule() {
+    let bus = VirtualBus::new();
+    let mut can = bus.node();
+    let mut scheduler = Scheduler::<2>::new();
+    scheduler.add(frame(0x100), 10_000, 0).unwrap();
+    let slot = scheduler.add(frame(0x200), 50_000, 0).unwrap();
+
+    scheduler.poll(&mut can, 0).unwrap();
+    scheduler.trigger(slot);
+    assert_eq!(scheduler.next_due(), Some(0));
+    assert_eq!(scheduler.poll(&mut can, 2_000).unwrap(), 1);
+    assert_eq!(scheduler.poll(&mut can, 3_000).unwrap(), 0);
+    assert_eq!(ids(&bus), [0x100, 0x200, 0x200]);
+
+    // Triggered frames don't count as sent on schedule.
+    assert_eq!(scheduler.stats(slot).unwrap().sent, 1);
+    assert_eq!(scheduler.next_due(), Some(10_000));
+    scheduler.poll(&mut can, 50_000).unwrap();
+    assert_eq!(scheduler.stats(slot).unwrap().sent, 2);
+}
+
+#[test]
+fn producer_is_asked_each_period() {
+    let bus = VirtualBus::new();
+    let mut can = bus.node();
+    let calls = Cell::new(0u16);
+    // Skips every other period.
+    let mut producer = || {
+        calls.set(calls.get() + 1);
+        (calls.get() % 2 == 1).then(|| frame(0x300 + calls.get()))
+    };
+    let mut scheduler = Scheduler::<1>::new();
+    scheduler.add_producer(&mut producer, 1_000, 0).unwrap();
+
+    let sent: usize = (0..4).map(|i| scheduler.poll(&mut can, i * 1_000).unwrap()).sum();
+    assert_eq!(sent, 2);
+    assert_eq!(ids(&bus), [0x301, 0x303]);
+    let stats = scheduler.stats(0).unwrap();
+    assert_eq!((stats.sent, stats.missed), (2, 0));
+    assert_eq!(scheduler.next_due(), Some(4_000));
+}
+
+#[test]
+fn slots_are_reused() {
+    let mut scheduler = Scheduler::<1>::new();
+    let slot = scheduler.add(frame(0x100), 1_000, 0).unwrap();
+    assert_eq!(scheduler.add(frame(0x200), 1_000, 0), None);
+    scheduler.remove(slot);
+    assert_eq!(scheduler.stats(slot), None);
+    assert_eq!(scheduler.add(frame(0x200), 1_000, 0), Some(slot));
+}
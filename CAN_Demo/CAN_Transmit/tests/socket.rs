@@ -0,0 +1,59 @@
+//! Conversions between `CanFrame` and SocketCAN frames. They don't touch a
+//! socket, so no interface is needed.
+
+use can2040::CanFrame;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+use socketcan::{CanErrorFrame, CanFrame as SocketFrame};
+
+/// Identifier, remote flag, DLC and data, the parts both frame types share.
+fn fields(frame: &impl Frame) -> (Id, bool, usize, Vec<u8>) {
+    (frame.id(), frame.is_remote_frame(), frame.dlc(), frame.data().to_vec())
+}
+
+fn round_trip(frame: CanFrame) -> SocketFrame {
+    let socket: SocketFrame = frame.into();
+    assert_eq!(fields(&socket), fields(&frame));
+    let back = CanFrame::try_from(socket).unwrap();
+    assert_eq!(fields(&back), fields(&frame));
+    socket
+}
+
+#[test]
+fn data_frames_with_dlc_0_and_8() {
+    let sid = StandardId::new(0x7FF).unwrap();
+    let empty = round_trip(CanFrame::new(sid, &[]).unwrap());
+    assert!(matches!(empty, SocketFrame::Data(_)));
+    assert_eq!(Frame::dlc(&empty), 0);
+
+    let full = round_trip(CanFrame::new(sid, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap());
+    assert_eq!(Frame::data(&full), [1, 2, 3, 4, 5, 6, 7, 8]);
+}
+
+#[test]
+fn extended_identifiers() {
+    let eid = ExtendedId::new(0x1234_5678).unwrap();
+    let socket = round_trip(CanFrame::new(eid, &[0xAA]).unwrap());
+    assert!(Frame::is_extended(&socket));
+    assert_eq!(Frame::id(&socket), Id::Extended(eid));
+
+    // A standard ID with the same value stays standard.
+    let sid = StandardId::new(0x078).unwrap();
+    assert!(!Frame::is_extended(&round_trip(CanFrame::new(sid, &[]).unwrap())));
+}
+
+#[test]
+fn remote_frames_keep_their_dlc() {
+    let sid = StandardId::new(0x123).unwrap();
+    let eid = ExtendedId::new(0x1ABC_DEF0).unwrap();
+    for dlc in [0, 4, 8] {
+        let socket = round_trip(CanFrame::new_remote(sid, dlc).unwrap());
+        assert!(matches!(socket, SocketFrame::Remote(_)));
+        round_trip(CanFrame::new_remote(eid, dlc).unwrap());
+    }
+}
+
+#[test]
+fn error_frames_are_decoded() {
+    let error = SocketFrame::Error(CanErrorFrame::new_error(0x0020, &[]).unwrap());
+    assert!(matches!(CanFrame::try_from(error), Err(socketcan::CanError::NoAck)));
+}
@@ -0,0 +1,71 @@
+//! Frame lengths and the sliding bus load window.
+
+use can2040::stats::{frame_bits, BusLoad, LOAD_WINDOW_US};
+use can2040::CanFrame;
+use embedded_can::{ExtendedId, Frame, StandardId};
+
+const BITRATE: u32 = 100_000;
+
+fn sid() -> StandardId {
+    StandardId::new(0x123).unwrap()
+}
+
+fn eid() -> ExtendedId {
+    ExtendedId::new(0x1234_5678).unwrap()
+}
+
+#[test]
+fn frame_bits_without_stuffing() {
+    assert_eq!(frame_bits(&CanFrame::new(sid(), &[]).unwrap()), 47);
+    assert_eq!(frame_bits(&CanFrame::new(sid(), &[0; 8]).unwrap()), 47 + 64);
+    assert_eq!(frame_bits(&CanFrame::new(eid(), &[]).unwrap()), 67);
+    assert_eq!(frame_bits(&CanFrame::new(eid(), &[0; 8]).unwrap()), 67 + 64);
+    // The DLC of a remote frame requests data, it doesn't carry any.
+    assert_eq!(frame_bits(&CanFrame::new_remote(sid(), 8).unwrap()), 47);
+    assert_eq!(frame_bits(&CanFrame::new_remote(eid(), 8).unwrap()), 67);
+}
+
+#[test]
+fn load_over_the_window_so_far() {
+    let mut load = BusLoad::new();
+    assert_eq!(load.permille(0, BITRATE), 0);
+    // 950 ms into the window there was room for 95 000 bits.
+    load.record(100_000, 4_750);
+    load.record(900_000, 4_750);
+    assert_eq!(load.permille(950_000, BITRATE), 100);
+}
+
+#[test]
+fn old_traffic_ages_out_one_slice_at_a_time() {
+    let mut load = BusLoad::new();
+    load.record(50_000, 9_500);
+    load.record(550_000, 9_500);
+    assert_eq!(load.permille(950_000, BITRATE), 200);
+    // The slice of the first frame has left the window, the second is still in.
+    assert_eq!(load.permille(LOAD_WINDOW_US + 50_000, BITRATE), 100);
+    assert_eq!(load.permille(LOAD_WINDOW_US + 550_000, BITRATE), 0);
+
+    // A long quiet gap clears the window in one go.
+    load.record(2_000_000, 9_500);
+    assert_eq!(load.permille(100 * LOAD_WINDOW_US + 950_000, BITRATE), 0);
+}
+
+#[test]
+fn load_follows_the_bitrate() {
+    let mut load = BusLoad::new();
+    load.record(500_000, 9_500);
+    assert_eq!(load.permille(950_000, BITRATE), 100);
+    assert_eq!(load.permille(950_000, 2 * BITRATE), 50);
+    // Traffic can't exceed the bus, however low the bitrate.
+    assert_eq!(load.permille(950_000, 1_000), 1000);
+    assert_eq!(load.permille(950_000, 0), 0);
+}
+
+#[test]
+fn clock_reset_starts_over() {
+    let mut load = BusLoad::new();
+    load.record(5_000_000, 50_000);
+    // e.g. a `hal::Timer` created after the bus was started.
+    load.record(150_000, 950);
+    assert_eq!(load.permille(950_000, BITRATE), 10);
+}
@@ -0,0 +1,114 @@
+//! Priority order of the software transmit queue.
+
+use can2040::tx_queue::{arbitration_key, TxQueue};
+use can2040::CanFrame;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+
+fn frame(id: u16, tag: u8) -> CanFrame {
+    CanFrame::new(StandardId::new(id).unwrap(), &[tag]).unwrap()
+}
+
+fn extended(id: u32) -> CanFrame {
+    CanFrame::new(ExtendedId::new(id).unwrap(), &[]).unwrap()
+}
+
+/// Identifier and first data byte of each frame left, in the order they pop.
+fn drain<const N: usize>(queue: &mut TxQueue<N>) -> Vec<(Id, u8)> {
+    std::iter::from_fn(|| queue.pop()).map(|f| (f.id(), f.data()[0])).collect()
+}
+
+fn sid(raw: u16) -> Id {
+    Id::Standard(StandardId::new(raw).unwrap())
+}
+
+#[test]
+fn pops_lowest_identifier_first() {
+    let mut queue = TxQueue::<4>::new();
+    for id in [0x300, 0x100, 0x7FF, 0x200] {
+        queue.push(frame(id, 0)).unwrap();
+    }
+    assert_eq!(queue.len(), 4);
+    let order: Vec<Id> = drain(&mut queue).into_iter().map(|(id, _)| id).collect();
+    assert_eq!(order, [sid(0x100), sid(0x200), sid(0x300), sid(0x7FF)]);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn equal_identifiers_leave_in_push_order() {
+    let mut queue = TxQueue::<4>::new();
+    queue.push(frame(0x200, 1)).unwrap();
+    queue.push(frame(0x100, 9)).unwrap();
+    queue.push(frame(0x200, 2)).unwrap();
+    assert_eq!(queue.pop().unwrap().data(), [9]);
+    // It goes into the slot just freed, between the other two, but still leaves last.
+    queue.push(frame(0x200, 3)).unwrap();
+    assert_eq!(drain(&mut queue), [(sid(0x200), 1), (sid(0x200), 2), (sid(0x200), 3)]);
+}
+
+#[test]
+fn push_hands_the_frame_back_when_full() {
+    let mut queue = TxQueue::<2>::new();
+    queue.push(frame(0x200, 1)).unwrap();
+    queue.push(frame(0x300, 2)).unwrap();
+    let rejected = queue.push(frame(0x100, 3)).unwrap_err();
+    assert_eq!(rejected.data(), [3]);
+    assert_eq!(queue.len(), 2);
+
+    queue.clear();
+    assert!(queue.is_empty() && queue.pop().is_none());
+}
+
+#[test]
+fn displaces_only_when_strictly_outranking() {
+    let mut queue = TxQueue::<2>::new();
+    assert!(queue.push_displacing(frame(0x200, 1)).unwrap().is_none());
+    queue.push(frame(0x300, 2)).unwrap();
+
+    // Lower priority, or a tie with the lowest queued frame: no room.
+    assert_eq!(queue.push_displacing(frame(0x400, 3)).unwrap_err().data(), [3]);
+    assert_eq!(queue.push_displacing(frame(0x300, 4)).unwrap_err().data(), [4]);
+
+    let displaced = queue.push_displacing(frame(0x250, 5)).unwrap().unwrap();
+    assert_eq!((displaced.id(), displaced.data()), (sid(0x300), &[2][..]));
+    assert_eq!(drain(&mut queue), [(sid(0x200), 1), (sid(0x250), 5)]);
+}
+
+#[test]
+fn displaces_the_newest_of_equal_lowest_frames() {
+    let mut queue = TxQueue::<2>::new();
+    queue.push(frame(0x300, 1)).unwrap();
+    queue.push(frame(0x300, 2)).unwrap();
+    let displaced = queue.push_displacing(frame(0x100, 3)).unwrap().unwrap();
+    assert_eq!(displaced.data(), [2]);
+    assert_eq!(drain(&mut queue), [(sid(0x100), 3), (sid(0x300), 1)]);
+}
+
+#[test]
+fn key_follows_bus_arbitration() {
+    let key = |frame: CanFrame| arbitration_key(&frame);
+    let remote = |id| CanFrame::new_remote(StandardId::new(id).unwrap(), 0).unwrap();
+
+    // The base ID decides first, whatever the format.
+    assert!(key(extended(0x0FF << 18)) < key(frame(0x100, 0)));
+    assert!(key(frame(0x100, 0)) < key(extended(0x101 << 18)));
+    // With the same base ID a standard frame wins through IDE, whatever the extension.
+    assert!(key(frame(0x123, 0)) < key(extended(0x123 << 18)));
+    assert!(key(remote(0x123)) < key(extended(0x123 << 18)));
+    // Then the extension bits, then data before remote.
+    assert!(key(extended(0x123 << 18)) < key(extended(0x123 << 18 | 1)));
+    assert!(key(frame(0x123, 0)) < key(remote(0x123)));
+    let ext_remote = CanFrame::new_remote(ExtendedId::new(0x1234_5678).unwrap(), 0).unwrap();
+    assert!(key(extended(0x1234_5678)) < key(ext_remote));
+    assert!(key(ext_remote) < key(extended(0x1234_5679)));
+}
+
+#[test]
+fn extended_frames_queue_behind_standard_ones() {
+    let mut queue = TxQueue::<3>::new();
+    queue.push(extended(0x100 << 18)).unwrap();
+    queue.push(frame(0x100, 7)).unwrap();
+    queue.push(extended(0x0FF << 18 | 0x3FFFF)).unwrap();
+    let order: Vec<Id> = std::iter::from_fn(|| queue.pop()).map(|f| f.id()).collect();
+    let eid = |raw| Id::Extended(ExtendedId::new(raw).unwrap());
+    assert_eq!(order, [eid(0x0FF << 18 | 0x3FFFF), sid(0x100), eid(0x100 << 18)]);
+}
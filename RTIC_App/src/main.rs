@@ -130,6 +130,20 @@ mod app {
     use embedded_sdmmc::filesystem::Mode;
     use embedded_hal_bus::spi::{ExclusiveDevice, NoDelay};
 
+    // CAN imports
+    use can2040::{Can2040, CanFrame};
+    use embedded_can::nb::Can;
+    use tgis::{AddressClaim, AnyMessage, Claimer, Heartbeat, Leak, Liveness, Message, NodeId};
+    use tgis::claim::{State, CLAIM_TIMEOUT_MS};
+    use tgis::liveness::{Event, PERIOD_MS};
+    use tgis::messages::NodeState;
+
+    const CAN_BITRATE: u32 = 10_000;
+    // The board type, and the node address claimed first
+    const BOARD: NodeId = NodeId::STATUS;
+    // A node is reported lost after this many heartbeat periods without one
+    const MISSED_HEARTBEATS: u32 = 3;
+
     // Dummy timesource for creating files
     #[derive(Default)]
     pub struct DummyTimesource();
@@ -176,6 +190,9 @@ mod app {
     struct DataShared {
         accel: Option<Lis3dhAccelerometer>,
         display: Option<OledDisplay>,
+        can: Can2040<pac::PIO0>,
+        liveness: Liveness,
+        claimer: Claimer,
         
         accel_mag: f32,
         leak_detected: bool,
@@ -190,6 +207,8 @@ mod app {
         pixel: (u8, u8),
         dirs: (bool, bool),
         sd_card_volume_mgr: Option<SdCardVolumeMgr>,
+        node_state: NodeState,
+        heartbeat_seq: u8,
     }
 
     // Systick magic
@@ -199,7 +218,7 @@ mod app {
 
     // Init function ------------------------------------------------------------------------------
     #[init(local = [i2c_bus: Option<I2cBus> = None])]
-    fn init(cx: init::Context) -> (DataShared, DataLocal, init::Monotonics) {
+    fn init(mut cx: init::Context) -> (DataShared, DataLocal, init::Monotonics) {
         
         info!("initializing");
 
@@ -230,8 +249,25 @@ mod app {
         let leak_detector_pin = pins.gpio1.into_pull_up_input();
         let leak_alarm_pin = pins.gpio2.into_push_pull_output();
 
+        // Flash unique ID, which tells identical boards apart when claiming a CAN address.
+        // Interrupts are still disabled and core 1 is idle, so nothing else touches the flash.
+        let mut unique_id = [0u8; 8];
+        unsafe { rp2040_flash::flash::flash_unique_id(&mut unique_id, true) };
+        info!("flash unique ID: {=[u8]:x}", unique_id);
+
         // Peripheral setup -----------------------------------------------------------------------
 
+        // CAN bus on GPIO 8 (RX) / GPIO 7 (TX), serviced by the `can_irq` hardware task
+        let can = Can2040::new(
+            cx.device.PIO0,
+            pins.gpio8,
+            pins.gpio7,
+            CAN_BITRATE,
+            &clocks.system_clock,
+            &mut resets,
+            &mut cx.core.NVIC,
+        );
+
         // I2C
         let scl = pins.gpio23.into_function::<FunctionI2C>().into_pull_type::<PullUp>();
         let sda = pins.gpio22.into_function::<FunctionI2C>().into_pull_type::<PullUp>();
@@ -319,6 +355,13 @@ mod app {
                 .spi(|spi_device| spi_device.bus_mut().set_baudrate(clocks.peripheral_clock.freq(), 16.MHz()));
         }
 
+        // Report a missing peripheral in our heartbeat
+        let node_state = if display.is_some() && lis3dh.is_some() && volume_mgr.is_some() {
+            NodeState::Operational
+        } else {
+            NodeState::Degraded
+        };
+
         // Task setup -----------------------------------------------------------------------------
         info!("Spawning tasks...");
         blink::spawn(5).unwrap();
@@ -332,6 +375,9 @@ mod app {
         // Start OLED animation
         update_oled::spawn_after(2000.millis()).unwrap();
         
+        // Claim a CAN address
+        claim_address::spawn().unwrap();
+
         // Start heartbeat
         heartbeat::spawn_after(3000.millis()).unwrap();
 
@@ -343,6 +389,9 @@ mod app {
             DataShared {
                 accel: lis3dh,
                 display: display,
+                can: can,
+                liveness: Liveness::new(MISSED_HEARTBEATS),
+                claimer: Claimer::new(AddressClaim::new(BOARD, unique_id)),
                 
                 accel_mag: 0.0f32,
                 leak_detected: false,
@@ -354,6 +403,8 @@ mod app {
                 pixel: (10, 25),
                 dirs: (true, true),
                 sd_card_volume_mgr: volume_mgr,
+                node_state: node_state,
+                heartbeat_seq: 0,
             },
             init::Monotonics(systick_monotonic::Systick::new(cx.core.SYST, 125_000_000)),
         )
@@ -362,12 +413,111 @@ mod app {
 
    
     // Heartbeat task -----------------------------------------------------------------------------
-    #[task]
-    fn heartbeat(_cx: heartbeat::Context) {
+    #[task(shared = [can, liveness, claimer], local = [node_state, heartbeat_seq])]
+    fn heartbeat(cx: heartbeat::Context) {
         trace!("heartbeat");
         blink::spawn(1).unwrap();
 
-        heartbeat::spawn_after(1000.millis()).unwrap();
+        // Broadcast our own heartbeat, once we have an address
+        let now = monotonics::now().ticks();
+        let beat = Heartbeat {
+            state: *cx.local.node_state,
+            sequence: *cx.local.heartbeat_seq,
+            uptime_s: (now / 1000) as u32,
+        };
+        *cx.local.heartbeat_seq = cx.local.heartbeat_seq.wrapping_add(1);
+        let mut claimer = cx.shared.claimer;
+        let node_id = claimer.lock(|claimer| claimer.address());
+        if let Some(node_id) = node_id {
+            let frame: CanFrame = beat.to_frame(node_id);
+            let mut can = cx.shared.can;
+            can.lock(|can| {
+                if let Err(e) = can.try_transmit(&frame) { warn!("unable to send heartbeat: {}", defmt::Debug2Format(&e)); }
+            });
+        }
+
+        // Supervise the other nodes, counting ourselves as one of them
+        let mut liveness = cx.shared.liveness;
+        liveness.lock(|liveness| {
+            if let Some(node_id) = node_id { liveness.heartbeat(node_id, &beat, now); }
+            for node in liveness.check(now) {
+                warn!("CAN node {} lost, no heartbeat for {} periods", node.as_raw(), MISSED_HEARTBEATS);
+            }
+        });
+
+        heartbeat::spawn_after(PERIOD_MS.millis()).unwrap();
+    }
+
+    // CAN address claim task --------------------------------------------------------------------
+    // Sends our claim, then runs again until it has gone uncontested. `can_rx` respawns it when
+    // another board takes our address.
+    #[task(shared = [can, claimer])]
+    fn claim_address(cx: claim_address::Context) {
+        let now = monotonics::now().ticks();
+        let claiming = (cx.shared.claimer, cx.shared.can).lock(|claimer, can| {
+            match claimer.state() {
+                State::Idle => {
+                    let frame: CanFrame = claimer.start(now);
+                    if let Err(e) = can.try_transmit(&frame) { warn!("unable to send address claim: {}", defmt::Debug2Format(&e)); }
+                }
+                State::CannotClaim => error!("no free CAN address, staying off the bus"),
+                _ => if let Some(address) = claimer.poll(now) { info!("claimed CAN node {}", address.as_raw()); },
+            }
+            matches!(claimer.state(), State::Claiming { .. })
+        });
+        if claiming {
+            claim_address::spawn_after(CLAIM_TIMEOUT_MS.millis()).unwrap();
+        }
+    }
+
+    // CAN interrupt task -------------------------------------------------------------------------
+    // can2040 does its bit timing in software, so it gets the highest priority.
+    #[task(binds = PIO0_IRQ_0, priority = 4, shared = [can])]
+    fn can_irq(mut cx: can_irq::Context) {
+        cx.shared.can.lock(|can| {
+            can.on_interrupt();
+            loop {
+                match can.receive() {
+                    Ok(frame) => {
+                        if can_rx::spawn(frame).is_err() {
+                            warn!("CAN RX task queue full, dropping frame");
+                        }
+                    }
+                    Err(nb::Error::Other(e)) => warn!("CAN error: {}", e),
+                    Err(nb::Error::WouldBlock) => break,
+                }
+            }
+        });
+    }
+
+    // CAN receive task ---------------------------------------------------------------------------
+    #[task(capacity = 8, shared = [liveness, claimer, can])]
+    fn can_rx(mut cx: can_rx::Context, frame: CanFrame) {
+        // Defend our address, or move on to another one
+        let now = monotonics::now().ticks();
+        (&mut cx.shared.claimer, &mut cx.shared.can).lock(|claimer, can| {
+            let address = claimer.address();
+            if let Some(reply) = claimer.receive::<CanFrame>(&frame, now) {
+                if let Err(e) = can.try_transmit(&reply) { warn!("unable to send address claim: {}", defmt::Debug2Format(&e)); }
+            }
+            if let (Some(lost), None) = (address, claimer.address()) {
+                warn!("CAN node {} taken by another board", lost.as_raw());
+                claim_address::spawn_after(CLAIM_TIMEOUT_MS.millis()).ok();
+            }
+        });
+
+        match AnyMessage::from_frame(&frame) {
+            Ok((node, AnyMessage::Heartbeat(beat))) => {
+                match cx.shared.liveness.lock(|liveness| liveness.heartbeat(node, &beat, now)) {
+                    Some(Event::Joined) => info!("CAN node {} joined: {}", node.as_raw(), beat.state),
+                    Some(Event::Returned) => info!("CAN node {} is back: {}", node.as_raw(), beat.state),
+                    Some(Event::Restarted) => warn!("CAN node {} restarted", node.as_raw()),
+                    None => trace!("CAN heartbeat from node {}: {}", node.as_raw(), beat),
+                }
+            }
+            Ok((node, message)) => trace!("CAN message from node {}: {}", node.as_raw(), message),
+            Err(e) => trace!("CAN frame received: {} ({})", frame, e),
+        }
     }
 
     // LED blink task -----------------------------------------------------------------------------
@@ -386,7 +536,7 @@ mod app {
     }
 
     // Leak detector task -------------------------------------------------------------------------
-    #[task (shared=[leak_detected], local=[leak_detector_pin, leak_alarm_pin])]
+    #[task (shared=[leak_detected, can, claimer], local=[leak_detector_pin, leak_alarm_pin])]
     fn update_leak_detector(cx: update_leak_detector::Context) {
         // Read the pin
         let leak = cx.local.leak_detector_pin.is_low().unwrap();
@@ -399,6 +549,16 @@ mod app {
         let mut leak_detected = cx.shared.leak_detected;
         leak_detected.lock(|leak_detected_l| {*leak_detected_l = leak});
 
+        // Broadcast it, once we have an address
+        let mut claimer = cx.shared.claimer;
+        if let Some(node_id) = claimer.lock(|claimer| claimer.address()) {
+            let mut can = cx.shared.can;
+            let frame: CanFrame = Leak { detected: leak }.to_frame(node_id);
+            can.lock(|can| {
+                if let Err(e) = can.try_transmit(&frame) { warn!("unable to send leak message: {}", defmt::Debug2Format(&e)); }
+            });
+        }
+
         update_leak_detector::spawn_after(500.millis()).unwrap();
     }
 
@@ -411,15 +571,17 @@ mod app {
     
     const VIBRATION_THRESHOLD: f32 = 2.0f32;
     
-    #[task(shared = [display, accel_mag, leak_detected], local = [pixel, dirs])]
+    #[task(shared = [display, accel_mag, leak_detected, liveness], local = [pixel, dirs])]
     fn update_oled(cx: update_oled::Context) {
-        // Change these values to view the interface prototypes //
-        let num_can_devices: u8 = 1;                            //
-        // ---------------------------------------------------- //
+        // Nodes with a recent heartbeat, ourselves included
+        let mut liveness = cx.shared.liveness;
+        let (num_can_devices, node_lost) = liveness.lock(|liveness_l| {
+            (liveness_l.alive().count(), liveness_l.lost().next().is_some())
+        });
 
         // 0 = nominal, 1 = warn, 2 = err
         let mut system_state = 0;
-        if num_can_devices == 0 { system_state = 1;}
+        if num_can_devices == 0 || node_lost { system_state = 1;}
         
         let mut leak_detected = cx.shared.leak_detected;
         let mut is_leak = false;
@@ -503,6 +665,11 @@ mod app {
                             Ok(_) => (),
                             Err(e) => error!("{}", defmt::Debug2Format(&e)),
                     }
+                } else if node_lost {
+                    match Text::new("CAN node lost!", START_POSITION_3, underline).draw(d_l) {
+                        Ok(_) => (),
+                        Err(e) => error!("{}", defmt::Debug2Format(&e)),
+                    }
                 } else if vibration_detected {
                     match Text::new("Vibration detected!", START_POSITION_3, underline).draw(d_l) {
                         Ok(_) => (),
@@ -0,0 +1,113 @@
+//! The catalogue as a Vector DBC file, for SavvyCAN, cantools, Wireshark and
+//! other CAN tools.
+//!
+//! A message's identifier includes its sender, so every sender gets its own
+//! `BO_` entry, named `<Message>_<node>`. Signals are little-endian (`@1`),
+//! with no scaling: factor 1, offset 0.
+
+use std::fmt::Write;
+
+use crate::spec::{Spec, Type};
+
+const NEW_SYMBOLS: &[&str] = &[
+    "NS_DESC_",
+    "CM_",
+    "BA_DEF_",
+    "BA_",
+    "VAL_",
+    "CAT_DEF_",
+    "CAT_",
+    "FILTER",
+    "BA_DEF_DEF_",
+    "EV_DATA_",
+    "ENVVAR_DATA_",
+    "SGTYPE_",
+    "SGTYPE_VAL_",
+    "BA_DEF_SGTYPE_",
+    "BA_SGTYPE_",
+    "SIG_TYPE_REF_",
+    "VAL_TABLE_",
+    "SIG_GROUP_",
+    "SIG_VALTYPE_",
+    "SIGTYPE_VALTYPE_",
+    "BO_TX_BU_",
+    "BA_DEF_REL_",
+    "BA_REL_",
+    "BA_DEF_DEF_REL_",
+    "BU_SG_REL_",
+    "BU_EV_REL_",
+    "BU_BO_REL_",
+    "SG_MUL_VAL_",
+];
+
+pub fn dbc(spec: &Spec) -> String {
+    let mut out = String::from("VERSION \"\"\n\n\nNS_ :\n");
+    for symbol in NEW_SYMBOLS {
+        writeln!(out, "\t{}", symbol).unwrap();
+    }
+    out += "\nBS_:\n\n";
+    let nodes: Vec<&str> = spec.nodes.iter().map(|n| n.name.as_str()).collect();
+    writeln!(out, "BU_: {}\n\n", nodes.join(" ")).unwrap();
+
+    // Every (message, sender) pair with its identifier.
+    let frames: Vec<_> = spec
+        .messages
+        .iter()
+        .flat_map(|m| m.senders.iter().map(move |s| (m, spec.node(s))))
+        .map(|(m, node)| (m, node, m.id(spec, node)))
+        .collect();
+
+    for (m, node, id) in &frames {
+        writeln!(out, "BO_ {} {}_{}: {} {}", id, m.name, node.name, m.len(), node.name).unwrap();
+        for f in &m.fields {
+            let l = f.layout;
+            let sign = if let Type::Signed = l.ty { '-' } else { '+' };
+            let (min, max) = f.range(spec);
+            let unit = f.unit.as_deref().unwrap_or("");
+            writeln!(
+                out,
+                " SG_ {} : {}|{}@1{} (1,0) [{}|{}] \"{}\" Vector__XXX",
+                f.name, l.start, l.bits, sign, min, max, unit
+            )
+            .unwrap();
+        }
+        out += "\n";
+    }
+    out += "\n";
+
+    for n in &spec.nodes {
+        writeln!(out, "CM_ BU_ {} \"{}\";", n.name, comment(&n.doc)).unwrap();
+    }
+    for (m, _, id) in &frames {
+        writeln!(out, "CM_ BO_ {} \"{}\";", id, comment(&m.doc)).unwrap();
+        for f in &m.fields {
+            writeln!(out, "CM_ SG_ {} {} \"{}\";", id, f.name, comment(&f.doc)).unwrap();
+        }
+    }
+
+    out += "BA_DEF_ BO_ \"GenMsgCycleTime\" INT 0 65535;\n";
+    out += "BA_DEF_DEF_ \"GenMsgCycleTime\" 0;\n";
+    for (m, _, id) in &frames {
+        if let Some(cycle) = m.cycle_ms {
+            writeln!(out, "BA_ \"GenMsgCycleTime\" BO_ {} {};", id, cycle).unwrap();
+        }
+    }
+
+    for (m, _, id) in &frames {
+        for f in &m.fields {
+            if let Type::Enum(i) = f.layout.ty {
+                write!(out, "VAL_ {} {}", id, f.name).unwrap();
+                for v in &spec.enums[i].values {
+                    write!(out, " {} \"{}\"", v.value, v.name).unwrap();
+                }
+                out += " ;\n";
+            }
+        }
+    }
+    out
+}
+
+/// DBC strings can't contain double quotes, and tools expect one line.
+fn comment(doc: &str) -> String {
+    doc.split_whitespace().collect::<Vec<_>>().join(" ").replace('"', "'")
+}
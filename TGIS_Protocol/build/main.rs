@@ -0,0 +1,29 @@
+//! Generates the catalogue from `messages.toml`: the Rust types included by
+//! `src/`, and `tgis.dbc` and `MESSAGES.md`, which `tests/generated.rs` checks
+//! against the copies in the repository.
+
+mod dbc;
+mod markdown;
+mod rust;
+mod spec;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-changed=messages.toml");
+    let text = fs::read_to_string("messages.toml").expect("Failed to read messages.toml");
+    let spec = spec::Spec::load(&text).unwrap_or_else(|e| panic!("messages.toml: {}", e));
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let files = [
+        ("id.rs", rust::ids(&spec)),
+        ("messages.rs", rust::messages(&spec)),
+        ("tgis.dbc", dbc::dbc(&spec)),
+        ("MESSAGES.md", markdown::markdown(&spec)),
+    ];
+    for (name, content) in files {
+        fs::write(out.join(name), content).unwrap_or_else(|e| panic!("{}: {}", name, e));
+    }
+}
@@ -0,0 +1,87 @@
+//! `MESSAGES.md`, the catalogue as tables for people.
+
+use std::fmt::Write;
+
+use crate::spec::Spec;
+
+pub fn markdown(spec: &Spec) -> String {
+    let mut out = String::new();
+    out += "<!-- Generated from messages.toml by build.rs; update with `TGIS_BLESS=1 cargo test`. -->\n";
+    out += "# TGIS messages\n\n";
+    out += "Identifiers are `priority << 8 | kind << 4 | node`, see the crate README. Multi-byte\n";
+    out += "fields are little-endian, and bits are numbered from the least significant bit of byte 0.\n";
+    out += "Receivers ignore bytes past a message's length. Any message can be requested with a\n";
+    out += "remote frame on its identifier.\n";
+
+    out += "\n## Priority classes\n\n| class | name | use |\n|-------|------|-----|\n";
+    for p in &spec.priorities {
+        writeln!(out, "| {} | {} | {} |", p.class, p.name, one_line(&p.doc)).unwrap();
+    }
+
+    out += "\n## Nodes\n\n| node | name | board |\n|------|------|-------|\n";
+    out += "| 0 | | unassigned |\n";
+    for n in &spec.nodes {
+        writeln!(out, "| {} | `{}` | {} |", n.id, n.name, one_line(&n.doc)).unwrap();
+    }
+
+    out += "\n## Messages\n\n";
+    out += "| message | class | kind | ID | bytes | cycle | senders |\n";
+    out += "|---------|-------|------|----|-------|-------|---------|\n";
+    for m in &spec.messages {
+        let class = spec.priority(&m.priority).class;
+        let cycle = m.cycle_ms.map(|ms| format!("{} ms", ms)).unwrap_or_default();
+        let senders: Vec<String> = m.senders.iter().map(|s| format!("`{}`", s)).collect();
+        writeln!(
+            out,
+            "| [{}](#{}) | {} | {} | `0x{}{:X}n` | {} | {} | {} |",
+            m.name,
+            m.name.to_lowercase(),
+            m.priority,
+            m.kind,
+            class,
+            m.kind,
+            m.len(),
+            cycle,
+            senders.join(", ")
+        )
+        .unwrap();
+    }
+
+    for m in &spec.messages {
+        writeln!(out, "\n### {}\n\n{}", m.name, one_line(&m.doc)).unwrap();
+        if m.fields.is_empty() {
+            continue;
+        }
+        out += "\n| bits | field | type | unit | description |\n";
+        out += "|------|-------|------|------|-------------|\n";
+        for f in &m.fields {
+            writeln!(
+                out,
+                "| {} | `{}` | {} | {} | {} |",
+                f.bit_range(),
+                f.name,
+                f.rust_type(spec),
+                f.unit.as_deref().unwrap_or(""),
+                one_line(&f.doc)
+            )
+            .unwrap();
+        }
+    }
+
+    if !spec.enums.is_empty() {
+        out += "\n## Enumerations\n";
+    }
+    for e in &spec.enums {
+        writeln!(out, "\n### {}\n\n{}\n", e.name, one_line(&e.doc)).unwrap();
+        out += "| value | name | description |\n|-------|------|-------------|\n";
+        for v in &e.values {
+            let doc = v.doc.as_deref().map(one_line).unwrap_or_default();
+            writeln!(out, "| {} | {} | {} |", v.value, v.name, doc).unwrap();
+        }
+    }
+    out
+}
+
+fn one_line(doc: &str) -> String {
+    doc.split_whitespace().collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,200 @@
+//! The Rust side of the catalogue, included by `src/id.rs` and
+//! `src/messages.rs`.
+
+use std::fmt::Write;
+
+use crate::spec::{Field, Message, Spec, Type};
+
+const HEADER: &str = "// Generated from messages.toml by build.rs.\n";
+const DERIVE: &str = "#[cfg_attr(feature = \"defmt\", derive(defmt::Format))]\n";
+
+/// `Priority`, `MessageKind` and the `NodeId` constants.
+pub fn ids(spec: &Spec) -> String {
+    let mut out = String::from(HEADER);
+
+    out += "\n/// Priority class, the top three identifier bits. Lower classes win\n";
+    out += "/// arbitration.\n";
+    out += "#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]\n";
+    out += DERIVE;
+    out += "#[repr(u8)]\npub enum Priority {\n";
+    for p in &spec.priorities {
+        doc(&mut out, "    ", &p.doc);
+        writeln!(out, "    {} = {},", p.name, p.class).unwrap();
+    }
+    out += "}\n";
+
+    out += "\n/// The message kinds of the catalogue, the middle four identifier bits. Each\n";
+    out += "/// kind always travels in the same priority class.\n";
+    out += "#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]\n";
+    out += DERIVE;
+    out += "#[repr(u8)]\npub enum MessageKind {\n";
+    for m in &spec.messages {
+        writeln!(out, "    /// [`{0}`](crate::messages::{0})\n    {0} = {1},", m.name, m.kind)
+            .unwrap();
+    }
+    out += "}\n";
+
+    out += "\nimpl MessageKind {\n";
+    let all: Vec<String> =
+        spec.messages.iter().map(|m| format!("MessageKind::{}", m.name)).collect();
+    writeln!(out, "    pub const ALL: [MessageKind; {}] = [{}];\n", all.len(), all.join(", "))
+        .unwrap();
+    out += "    pub const fn priority(self) -> Priority {\n        match self {\n";
+    for m in &spec.messages {
+        writeln!(out, "            MessageKind::{} => Priority::{},", m.name, m.priority).unwrap();
+    }
+    out += "        }\n    }\n}\n";
+
+    out += "\nimpl NodeId {\n";
+    for n in &spec.nodes {
+        doc(&mut out, "    ", &n.doc);
+        writeln!(out, "    pub const {}: NodeId = NodeId({});", n.name.to_uppercase(), n.id)
+            .unwrap();
+    }
+    out += "}\n";
+    out
+}
+
+/// The enums, a struct and `Message` impl per message, and `AnyMessage`.
+pub fn messages(spec: &Spec) -> String {
+    let mut out = String::from(HEADER);
+
+    for e in &spec.enums {
+        out += "\n";
+        doc(&mut out, "", &e.doc);
+        out += "#[derive(Clone, Copy, Debug, PartialEq, Eq)]\n";
+        out += DERIVE;
+        writeln!(out, "#[repr(u8)]\npub enum {} {{", e.name).unwrap();
+        for v in &e.values {
+            if let Some(text) = &v.doc {
+                doc(&mut out, "    ", text);
+            }
+            writeln!(out, "    {} = {},", v.name, v.value).unwrap();
+        }
+        out += "}\n";
+
+        writeln!(out, "\nimpl {} {{", e.name).unwrap();
+        out += "    pub fn from_raw(raw: u8) -> Option<Self> {\n        match raw {\n";
+        for v in &e.values {
+            writeln!(out, "            {} => Some({}::{}),", v.value, e.name, v.name).unwrap();
+        }
+        out += "            _ => None,\n        }\n    }\n}\n";
+    }
+
+    for m in &spec.messages {
+        message(&mut out, spec, m);
+    }
+
+    out += "\n/// Any message of the catalogue, for receivers that take whatever arrives.\n";
+    out += "#[derive(Clone, Copy, Debug, PartialEq, Eq)]\n";
+    out += DERIVE;
+    out += "pub enum AnyMessage {\n";
+    for m in &spec.messages {
+        writeln!(out, "    {0}({0}),", m.name).unwrap();
+    }
+    out += "}\n";
+
+    out += "\nimpl AnyMessage {\n";
+    out += "    pub fn kind(&self) -> MessageKind {\n        match self {\n";
+    for m in &spec.messages {
+        writeln!(out, "            AnyMessage::{0}(_) => MessageKind::{0},", m.name).unwrap();
+    }
+    out += "        }\n    }\n\n";
+    out += "    pub fn to_frame<F: Frame>(&self, node: NodeId) -> F {\n        match self {\n";
+    for m in &spec.messages {
+        writeln!(out, "            AnyMessage::{}(m) => m.to_frame(node),", m.name).unwrap();
+    }
+    out += "        }\n    }\n\n";
+    out += "    /// Decodes whichever message `frame` carries, and returns the sender too.\n";
+    out += "    pub fn from_frame<F: Frame>(frame: &F) -> Result<(NodeId, Self), DecodeError> {\n";
+    out += "        let id = MessageId::try_from(frame.id())?;\n";
+    out += "        let message = match id.kind {\n";
+    for m in &spec.messages {
+        writeln!(
+            out,
+            "            MessageKind::{0} => AnyMessage::{0}(decode_data(frame)?),",
+            m.name
+        )
+        .unwrap();
+    }
+    out += "        };\n        Ok((id.node, message))\n    }\n}\n";
+    out
+}
+
+fn message(out: &mut String, spec: &Spec, m: &Message) {
+    out.push('\n');
+    doc(out, "", &m.doc);
+    if !m.fields.is_empty() {
+        *out += "///\n/// | bits | field | type | unit |\n/// |------|-------|------|------|\n";
+        for f in &m.fields {
+            writeln!(
+                out,
+                "/// | {} | `{}` | `{}` | {} |",
+                f.bit_range(),
+                f.name,
+                f.rust_type(spec),
+                f.unit.as_deref().unwrap_or("")
+            )
+            .unwrap();
+        }
+    }
+    *out += "#[derive(Clone, Copy, Debug, PartialEq, Eq)]\n";
+    *out += DERIVE;
+    writeln!(out, "pub struct {} {{", m.name).unwrap();
+    for f in &m.fields {
+        doc(out, "    ", &f.doc);
+        writeln!(out, "    pub {}: {},", f.name, f.rust_type(spec)).unwrap();
+    }
+    *out += "}\n";
+
+    let data = if m.fields.is_empty() { "_data" } else { "data" };
+    writeln!(out, "\nimpl Message for {} {{", m.name).unwrap();
+    writeln!(out, "    const KIND: MessageKind = MessageKind::{};", m.name).unwrap();
+    writeln!(out, "    const LEN: usize = {};", m.len()).unwrap();
+    if let Some(cycle) = m.cycle_ms {
+        writeln!(out, "    const CYCLE_MS: Option<u32> = Some({});", cycle).unwrap();
+    }
+    writeln!(out, "\n    fn encode(&self, {}: &mut [u8]) {{", data).unwrap();
+    for f in &m.fields {
+        let l = f.layout;
+        writeln!(out, "        put_bits(data, {}, {}, {});", l.start, l.bits, encode(f)).unwrap();
+    }
+    *out += "    }\n";
+    writeln!(out, "\n    fn decode({}: &[u8]) -> Result<Self, DecodeError> {{", data).unwrap();
+    writeln!(out, "        Ok({} {{", m.name).unwrap();
+    for f in &m.fields {
+        writeln!(out, "            {}: {},", f.name, decode(spec, f)).unwrap();
+    }
+    *out += "        })\n    }\n}\n";
+}
+
+fn encode(f: &Field) -> String {
+    match f.layout.ty {
+        Type::Signed => format!("self.{} as u{} as u64", f.name, f.layout.width),
+        Type::Unsigned if f.layout.width == 64 => format!("self.{}", f.name),
+        _ => format!("self.{} as u64", f.name),
+    }
+}
+
+fn decode(spec: &Spec, f: &Field) -> String {
+    let l = f.layout;
+    let raw = format!("get_bits(data, {}, {})", l.start, l.bits);
+    match l.ty {
+        Type::Bool => format!("{} != 0", raw),
+        Type::Unsigned if l.width == 64 => raw,
+        Type::Unsigned => format!("{} as u{}", raw, l.width),
+        Type::Signed => format!("{} as u{1} as i{1}", raw, l.width),
+        Type::Enum(i) => format!(
+            "{}::from_raw({} as u8).ok_or(DecodeError::InvalidValue {{ offset: {} }})?",
+            spec.enums[i].name,
+            raw,
+            l.start / 8
+        ),
+    }
+}
+
+fn doc(out: &mut String, indent: &str, text: &str) {
+    for line in text.lines() {
+        writeln!(out, "{}/// {}", indent, line).unwrap();
+    }
+}
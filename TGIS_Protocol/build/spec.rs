@@ -0,0 +1,251 @@
+//! `messages.toml`: what it may contain, and the checks that keep the
+//! catalogue consistent before anything is generated from it.
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    pub priorities: Vec<Priority>,
+    pub nodes: Vec<Node>,
+    #[serde(default)]
+    pub enums: Vec<Enum>,
+    pub messages: Vec<Message>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Priority {
+    pub name: String,
+    pub class: u8,
+    pub doc: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Node {
+    pub name: String,
+    pub id: u8,
+    pub doc: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Enum {
+    pub name: String,
+    pub doc: String,
+    pub values: Vec<EnumValue>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnumValue {
+    pub name: String,
+    pub value: u8,
+    pub doc: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Message {
+    pub name: String,
+    pub kind: u8,
+    pub priority: String,
+    pub senders: Vec<String>,
+    pub cycle_ms: Option<u32>,
+    pub doc: String,
+    #[serde(default)]
+    pub fields: Vec<Field>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub bits: Option<u32>,
+    pub unit: Option<String>,
+    pub doc: String,
+    /// Filled in by `Spec::load`.
+    #[serde(skip)]
+    pub layout: Layout,
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum Type {
+    #[default]
+    Bool,
+    Unsigned,
+    Signed,
+    /// Index into `Spec::enums`.
+    Enum(usize),
+}
+
+/// Where a field sits in the data and how it is read.
+#[derive(Clone, Copy, Default)]
+pub struct Layout {
+    pub ty: Type,
+    pub start: u32,
+    pub bits: u32,
+    /// Width of the Rust type, which is wider than `bits` for narrowed fields.
+    pub width: u32,
+}
+
+impl Spec {
+    pub fn load(text: &str) -> Result<Spec, String> {
+        let mut spec: Spec = toml::from_str(text).map_err(|e| e.to_string())?;
+        spec.check()?;
+        Ok(spec)
+    }
+
+    pub fn priority(&self, name: &str) -> &Priority {
+        self.priorities.iter().find(|p| p.name == name).unwrap()
+    }
+
+    pub fn node(&self, name: &str) -> &Node {
+        self.nodes.iter().find(|n| n.name == name).unwrap()
+    }
+
+    fn check(&mut self) -> Result<(), String> {
+        unique("priority", self.priorities.iter().map(|p| (p.name.clone(), p.class as u32)))?;
+        for p in &self.priorities {
+            if p.class > 7 {
+                return Err(format!("priority {}: class must be 0..=7", p.name));
+            }
+        }
+
+        unique("node", self.nodes.iter().map(|n| (n.name.clone(), n.id as u32)))?;
+        for n in &self.nodes {
+            if !(1..=15).contains(&n.id) {
+                return Err(format!("node {}: id must be 1..=15", n.name));
+            }
+        }
+
+        unique("enum", self.enums.iter().enumerate().map(|(i, e)| (e.name.clone(), i as u32)))?;
+        for e in &self.enums {
+            let values = e.values.iter().map(|v| (v.name.clone(), v.value as u32));
+            unique(&format!("{} value", e.name), values)?;
+        }
+
+        unique("message", self.messages.iter().map(|m| (m.name.clone(), m.kind as u32)))?;
+        for m in &self.messages {
+            if !(1..=15).contains(&m.kind) {
+                return Err(format!("message {}: kind must be 1..=15", m.name));
+            }
+            if !self.priorities.iter().any(|p| p.name == m.priority) {
+                return Err(format!("message {}: unknown priority {}", m.name, m.priority));
+            }
+            if let Some(sender) =
+                m.senders.iter().find(|s| !self.nodes.iter().any(|n| &n.name == *s))
+            {
+                return Err(format!("message {}: unknown sender {}", m.name, sender));
+            }
+            let fields = m.fields.iter().enumerate().map(|(i, f)| (f.name.clone(), i as u32));
+            unique(&format!("{} field", m.name), fields)?;
+        }
+
+        let enums = &self.enums;
+        for m in &mut self.messages {
+            let mut start = 0;
+            for f in &mut m.fields {
+                f.layout =
+                    layout(enums, f, start).map_err(|e| format!("{}.{}: {}", m.name, f.name, e))?;
+                start += f.layout.bits;
+            }
+            if start > 64 {
+                return Err(format!("message {}: {} bits don't fit in 8 bytes", m.name, start));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Message {
+    /// Data bytes sent.
+    pub fn len(&self) -> u32 {
+        self.fields.iter().map(|f| f.layout.bits).sum::<u32>().div_ceil(8)
+    }
+
+    pub fn id(&self, spec: &Spec, node: &Node) -> u16 {
+        (spec.priority(&self.priority).class as u16) << 8 | (self.kind as u16) << 4 | node.id as u16
+    }
+}
+
+impl Field {
+    /// `3` or `8-15`.
+    pub fn bit_range(&self) -> String {
+        let Layout { start, bits, .. } = self.layout;
+        match bits {
+            1 => start.to_string(),
+            _ => format!("{}-{}", start, start + bits - 1),
+        }
+    }
+
+    /// The Rust type.
+    pub fn rust_type<'a>(&'a self, spec: &'a Spec) -> &'a str {
+        match self.layout.ty {
+            Type::Enum(i) => &spec.enums[i].name,
+            _ => &self.ty,
+        }
+    }
+
+    /// The raw range, as a DBC signal states it.
+    pub fn range(&self, spec: &Spec) -> (i128, i128) {
+        let Layout { ty, bits, .. } = self.layout;
+        match ty {
+            Type::Bool => (0, 1),
+            Type::Unsigned => (0, (1i128 << bits) - 1),
+            Type::Signed => (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1),
+            Type::Enum(i) => {
+                let values = spec.enums[i].values.iter().map(|v| v.value as i128);
+                (0, values.max().unwrap_or(0))
+            }
+        }
+    }
+}
+
+fn layout(enums: &[Enum], field: &Field, start: u32) -> Result<Layout, String> {
+    let (ty, width) = match field.ty.as_str() {
+        "bool" => (Type::Bool, 1),
+        "u8" => (Type::Unsigned, 8),
+        "u16" => (Type::Unsigned, 16),
+        "u32" => (Type::Unsigned, 32),
+        "u64" => (Type::Unsigned, 64),
+        "i8" => (Type::Signed, 8),
+        "i16" => (Type::Signed, 16),
+        "i32" => (Type::Signed, 32),
+        name => match enums.iter().position(|e| e.name == name) {
+            Some(i) => (Type::Enum(i), 8),
+            None => return Err(format!("unknown type {}", name)),
+        },
+    };
+    let bits = field.bits.unwrap_or(width);
+    match ty {
+        Type::Unsigned | Type::Enum(_) if (1..=width).contains(&bits) => {}
+        _ if bits == width => {}
+        _ => return Err(format!("{} can't be {} bits", field.ty, bits)),
+    }
+    if let Type::Enum(i) = ty {
+        if let Some(v) = enums[i].values.iter().find(|v| bits < 8 && v.value >> bits != 0) {
+            return Err(format!("{} doesn't fit in {} bits", v.name, bits));
+        }
+    }
+    Ok(Layout { ty, start, bits, width })
+}
+
+/// Fails if two entries share a name or a number.
+fn unique(what: &str, entries: impl Iterator<Item = (String, u32)>) -> Result<(), String> {
+    let (mut names, mut numbers) = (HashSet::new(), HashSet::new());
+    for (name, number) in entries {
+        if !names.insert(name.clone()) {
+            return Err(format!("duplicate {} name {}", what, name));
+        }
+        if !numbers.insert(number) {
+            return Err(format!("{} {}: number {} already used", what, name, number));
+        }
+    }
+    Ok(())
+}
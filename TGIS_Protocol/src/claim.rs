@@ -0,0 +1,188 @@
+//! Node addresses claimed at boot, as in J1939.
+//!
+//! Each board prefers the node its board type is named after, but two identical
+//! boards on one bus can't both have it. A [`Claimer`] sends an
+//! [`AddressClaim`] with the board's name on its preferred address. A board
+//! that holds or claims the same address compares names: the lower name claims
+//! the address again and keeps it, the other moves on to the next address it
+//! could win: one that is free, or held by a higher name. Data frames from a
+//! board that never claimed its address count as a claim with the lowest name,
+//! 0, so that address is never won. An address may be used once its claim has
+//! gone uncontested for [`CLAIM_TIMEOUT_MS`]. If every address is held by a
+//! lower name, the board says so with a claim on node 0 and stays off the bus.
+//!
+//! A remote frame for `AddressClaim` on node 0 asks every board to repeat its
+//! claim, which enumerates the nodes on the bus.
+
+use embedded_can::Frame;
+
+use crate::id::{MessageId, MessageKind, NodeId};
+use crate::messages::{AddressClaim, Message};
+
+/// How long a claim must go uncontested before the address is used.
+pub const CLAIM_TIMEOUT_MS: u64 = 250;
+
+/// Where a [`Claimer`] is in claiming an address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    /// [`Claimer::start`] hasn't been called yet.
+    Idle,
+    /// Claim sent at `since_ms`, waiting to see whether anyone contests it.
+    Claiming {
+        address: NodeId,
+        since_ms: u64,
+    },
+    Claimed(NodeId),
+    /// Every address is taken by a board with a lower name.
+    CannotClaim,
+}
+
+/// Claims and defends the address of one board. It only builds frames; the
+/// caller sends them and feeds it what it receives.
+#[derive(Clone, Debug)]
+pub struct Claimer {
+    name: AddressClaim,
+    state: State,
+    /// The name last heard claiming each address. Data frames from a node
+    /// that never claimed its address count as the lowest name, 0.
+    holders: [Option<u64>; NodeId::MAX.as_raw() as usize + 1],
+}
+
+impl Claimer {
+    pub fn new(name: AddressClaim) -> Self {
+        Self { name, state: State::Idle, holders: [None; NodeId::MAX.as_raw() as usize + 1] }
+    }
+
+    pub fn name(&self) -> &AddressClaim {
+        &self.name
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The address to send with, once it has been claimed.
+    pub fn address(&self) -> Option<NodeId> {
+        match self.state {
+            State::Claimed(address) => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Claims the preferred address at `now_ms`, or the next one we could win
+    /// if it is already held or the board is `UNASSIGNED`, and returns the
+    /// claim to send.
+    pub fn start<F: Frame>(&mut self, now_ms: u64) -> F {
+        // The name was made from a NodeId, so the board fits.
+        let preferred = NodeId::new(self.name.board).unwrap_or(NodeId::UNASSIGNED);
+        if preferred != NodeId::UNASSIGNED && self.could_win(preferred.as_raw()) {
+            self.claim(preferred, now_ms)
+        } else {
+            self.move_on(preferred, now_ms)
+        }
+    }
+
+    /// Completes a claim that has gone uncontested, and returns the address
+    /// that was claimed.
+    pub fn poll(&mut self, now_ms: u64) -> Option<NodeId> {
+        match self.state {
+            State::Claiming { address, since_ms }
+                if now_ms.saturating_sub(since_ms) >= CLAIM_TIMEOUT_MS =>
+            {
+                self.state = State::Claimed(address);
+                Some(address)
+            }
+            _ => None,
+        }
+    }
+
+    /// Handles a frame received at `now_ms`, and returns the frame to send in
+    /// reply, if any.
+    ///
+    /// Frames this board sent itself must not be passed in, such as can2040's
+    /// `TimestampedFrame::echo` with loopback on: on the address we claim or
+    /// hold they would look like a node that never claimed it, and we would
+    /// move on.
+    pub fn receive<F: Frame>(&mut self, frame: &F, now_ms: u64) -> Option<F> {
+        let id = MessageId::try_from(frame.id()).ok()?;
+        if id.kind != MessageKind::AddressClaim {
+            // A remote frame asks the node rather than coming from it.
+            if frame.is_remote_frame() {
+                return None;
+            }
+            self.holders[id.node.as_raw() as usize].get_or_insert(0);
+            if Some(id.node) == self.claimed_or_claiming() {
+                // Someone sends with our address without claiming it; its
+                // name of 0 beats ours.
+                return Some(self.move_on(id.node, now_ms));
+            }
+            return None;
+        }
+        if frame.is_remote_frame() {
+            let asked =
+                id.node == NodeId::UNASSIGNED || Some(id.node) == self.claimed_or_claiming();
+            return (asked && self.state != State::Idle).then(|| self.announce());
+        }
+
+        let (node, claim) = AddressClaim::from_frame(frame).ok()?;
+        if claim == self.name {
+            // Our own claim, looped back.
+            return None;
+        }
+        if node != NodeId::UNASSIGNED {
+            // A board claims one address at a time.
+            self.holders.iter_mut().filter(|h| **h == Some(claim.name())).for_each(|h| *h = None);
+            self.holders[node.as_raw() as usize] = Some(claim.name());
+        }
+        if Some(node) != self.claimed_or_claiming() {
+            return None;
+        }
+        if self.name.name() < claim.name() {
+            Some(self.announce())
+        } else {
+            Some(self.move_on(node, now_ms))
+        }
+    }
+
+    fn claimed_or_claiming(&self) -> Option<NodeId> {
+        match self.state {
+            State::Claiming { address, .. } | State::Claimed(address) => Some(address),
+            State::Idle | State::CannotClaim => None,
+        }
+    }
+
+    /// Claims the next address after `lost` that we could win, starting from
+    /// 1 if `lost` is `UNASSIGNED`.
+    fn move_on<F: Frame>(&mut self, lost: NodeId, now_ms: u64) -> F {
+        let max = NodeId::MAX.as_raw();
+        let free = (1..=max)
+            .map(|i| (lost.as_raw() + i - 1) % max + 1)
+            .filter(|&address| address != lost.as_raw())
+            .find(|&address| self.could_win(address))
+            .and_then(NodeId::new);
+        match free {
+            Some(address) => self.claim(address, now_ms),
+            None => {
+                self.state = State::CannotClaim;
+                self.announce()
+            }
+        }
+    }
+
+    /// Whether `address` is free or held by a higher name than ours.
+    fn could_win(&self, address: u8) -> bool {
+        self.holders[address as usize].is_none_or(|h| h > self.name.name())
+    }
+
+    fn claim<F: Frame>(&mut self, address: NodeId, now_ms: u64) -> F {
+        self.state = State::Claiming { address, since_ms: now_ms };
+        self.announce()
+    }
+
+    /// Our claim on the address we hold or are claiming, or on node 0 if we
+    /// have none.
+    fn announce<F: Frame>(&self) -> F {
+        self.name.to_frame(self.claimed_or_claiming().unwrap_or(NodeId::UNASSIGNED))
+    }
+}
@@ -0,0 +1,103 @@
+//! Identifier allocation.
+//!
+//! Every TGIS message uses an 11-bit standard identifier made of three fields:
+//!
+//! | bits 10..8 | bits 7..4    | bits 3..0 |
+//! |------------|--------------|-----------|
+//! | priority   | message kind | node      |
+//!
+//! Arbitration lets the lowest identifier through first, so the priority class
+//! decides which message wins, then the kind, then the sender. The node in the
+//! low bits keeps identifiers unique, since two nodes must never send the same
+//! one. Extended identifiers are left free for other protocols on the bus.
+
+use embedded_can::{Id, StandardId};
+
+use crate::DecodeError;
+
+/// The sending board, 0..=15. The boards' constants are generated from
+/// `messages.toml`; they are the addresses the boards claim first, see
+/// [`claim`](crate::claim).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NodeId(u8);
+
+impl NodeId {
+    /// Not assigned to a board; a node that has no address yet.
+    pub const UNASSIGNED: NodeId = NodeId(0);
+    pub const MAX: NodeId = NodeId(15);
+
+    /// Returns `None` if `id` doesn't fit in the 4-bit node field.
+    pub const fn new(id: u8) -> Option<Self> {
+        if id <= Self::MAX.0 {
+            Some(NodeId(id))
+        } else {
+            None
+        }
+    }
+
+    pub const fn as_raw(self) -> u8 {
+        self.0
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/id.rs"));
+
+impl MessageKind {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| *kind as u8 == raw)
+    }
+}
+
+/// A TGIS identifier: which message, from which node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MessageId {
+    pub kind: MessageKind,
+    pub node: NodeId,
+}
+
+impl MessageId {
+    pub const fn new(kind: MessageKind, node: NodeId) -> Self {
+        Self { kind, node }
+    }
+
+    pub const fn priority(&self) -> Priority {
+        self.kind.priority()
+    }
+
+    pub const fn as_raw(&self) -> u16 {
+        (self.priority() as u16) << 8 | (self.kind as u16) << 4 | self.node.0 as u16
+    }
+
+    /// Fails for identifiers that are not in the catalogue, including known
+    /// kinds in the wrong priority class.
+    pub fn from_raw(raw: u16) -> Result<Self, DecodeError> {
+        let kind = MessageKind::from_raw((raw >> 4 & 0xF) as u8)
+            .filter(|kind| kind.priority() as u16 == raw >> 8)
+            .ok_or(DecodeError::UnknownId(raw))?;
+        Ok(Self { kind, node: NodeId((raw & 0xF) as u8) })
+    }
+
+    pub fn standard_id(&self) -> StandardId {
+        // The generator keeps priority classes below 8, so this fits in 11 bits.
+        StandardId::new(self.as_raw()).unwrap()
+    }
+}
+
+impl From<MessageId> for Id {
+    fn from(id: MessageId) -> Self {
+        Id::Standard(id.standard_id())
+    }
+}
+
+impl TryFrom<Id> for MessageId {
+    type Error = DecodeError;
+
+    fn try_from(id: Id) -> Result<Self, DecodeError> {
+        match id {
+            Id::Standard(id) => Self::from_raw(id.as_raw()),
+            Id::Extended(_) => Err(DecodeError::Extended),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Messages exchanged on the TailGator Interconnect System CAN bus.
+//!
+//! Shared by the board firmware and the host tools, so that every node agrees
+//! on identifiers and data layouts. The catalogue is generated from
+//! `messages.toml`, which also produces `tgis.dbc` for CAN tools and the
+//! tables in `MESSAGES.md`. Frames are built and read through the
+//! `embedded_can::Frame` trait, which works with `can2040::CanFrame` on the
+//! RP2040 as well as SocketCAN frames on a computer:
+//!
+//! ```ignore
+//! use tgis::{Leak, Message, NodeId};
+//!
+//! let frame: CanFrame = Leak { detected: true }.to_frame(NodeId::STATUS);
+//! let (sender, leak) = Leak::from_frame(&frame)?;
+//! ```
+#![no_std]
+
+pub mod claim;
+pub mod id;
+pub mod liveness;
+pub mod messages;
+
+pub use claim::Claimer;
+pub use id::{MessageId, MessageKind, NodeId, Priority};
+pub use liveness::Liveness;
+pub use messages::{AddressClaim, AnyMessage, Heartbeat, Imu, Leak, Message, NodeState, Power};
+
+/// Why a frame could not be read as a TGIS message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// Extended identifiers are not used by TGIS.
+    Extended,
+    /// A standard identifier that is not in the catalogue.
+    UnknownId(u16),
+    /// The frame carries a different message than the one asked for.
+    WrongKind { expected: MessageKind, found: MessageKind },
+    /// A remote frame, which requests the message instead of carrying it.
+    Remote,
+    /// Fewer data bytes than the message needs.
+    Length { expected: usize, actual: usize },
+    /// The byte at `offset` holds a value the message doesn't define.
+    InvalidValue { offset: usize },
+}
@@ -0,0 +1,129 @@
+//! Supervision of the other nodes through their heartbeats.
+//!
+//! Every node broadcasts a [`Heartbeat`] each `Heartbeat::CYCLE_MS`. A
+//! [`Liveness`] monitor is fed the heartbeats it receives and is checked
+//! periodically; a node that misses too many periods in a row is marked lost
+//! until it is heard from again. Time is passed in as milliseconds from any
+//! monotonic clock, so the monitor works the same on a board and on a host.
+
+use crate::id::NodeId;
+use crate::messages::{Heartbeat, Message, NodeState};
+
+/// The heartbeat period.
+pub const PERIOD_MS: u64 = match Heartbeat::CYCLE_MS {
+    Some(ms) => ms as u64,
+    None => panic!("Heartbeat has no cycle time"),
+};
+
+/// What the monitor knows about a node it has heard from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Peer {
+    /// The state from the last heartbeat.
+    pub state: NodeState,
+    /// The uptime from the last heartbeat.
+    pub uptime_s: u32,
+    pub last_seen_ms: u64,
+    /// Missed the allowed number of periods, and not heard from since.
+    pub lost: bool,
+}
+
+/// A change in a node's liveness, noticed when one of its heartbeats arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// The first heartbeat from this node.
+    Joined,
+    /// Heard from again after being lost.
+    Returned,
+    /// Its uptime went backwards, so it rebooted between two heartbeats.
+    Restarted,
+}
+
+/// Tracks the heartbeats of every node on the bus.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    nodes: [Option<Peer>; NodeId::MAX.as_raw() as usize + 1],
+    timeout_ms: u64,
+}
+
+impl Liveness {
+    /// A node is lost once it has missed `missed` heartbeats in a row.
+    pub const fn new(missed: u32) -> Self {
+        Self {
+            nodes: [None; NodeId::MAX.as_raw() as usize + 1],
+            timeout_ms: missed as u64 * PERIOD_MS,
+        }
+    }
+
+    /// Records a heartbeat from `node`, received at `now_ms`.
+    pub fn heartbeat(&mut self, node: NodeId, beat: &Heartbeat, now_ms: u64) -> Option<Event> {
+        let slot = &mut self.nodes[node.as_raw() as usize];
+        let event = match slot {
+            None => Some(Event::Joined),
+            Some(peer) if peer.lost => Some(Event::Returned),
+            Some(peer) if beat.uptime_s < peer.uptime_s => Some(Event::Restarted),
+            Some(_) => None,
+        };
+        *slot = Some(Peer {
+            state: beat.state,
+            uptime_s: beat.uptime_s,
+            last_seen_ms: now_ms,
+            lost: false,
+        });
+        event
+    }
+
+    /// Marks the nodes that have been quiet for too long at `now_ms` as lost,
+    /// and returns those that weren't lost yet.
+    pub fn check(&mut self, now_ms: u64) -> Lost {
+        let mut lost = 0;
+        for (i, peer) in self.nodes.iter_mut().enumerate() {
+            if let Some(peer) = peer {
+                if !peer.lost && now_ms.saturating_sub(peer.last_seen_ms) > self.timeout_ms {
+                    peer.lost = true;
+                    lost |= 1 << i;
+                }
+            }
+        }
+        Lost(lost)
+    }
+
+    pub fn peer(&self, node: NodeId) -> Option<&Peer> {
+        self.nodes[node.as_raw() as usize].as_ref()
+    }
+
+    /// The nodes heard from and not lost.
+    pub fn alive(&self) -> impl Iterator<Item = (NodeId, &Peer)> {
+        self.peers().filter(|(_, peer)| !peer.lost)
+    }
+
+    /// The nodes that are currently lost.
+    pub fn lost(&self) -> impl Iterator<Item = (NodeId, &Peer)> {
+        self.peers().filter(|(_, peer)| peer.lost)
+    }
+
+    fn peers(&self) -> impl Iterator<Item = (NodeId, &Peer)> {
+        self.nodes
+            .iter()
+            .zip(0..)
+            .filter_map(|(peer, i)| Some((NodeId::new(i).unwrap(), peer.as_ref()?)))
+    }
+}
+
+/// The nodes newly lost in one [`Liveness::check`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lost(u16);
+
+impl Iterator for Lost {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        if self.0 == 0 {
+            return None;
+        }
+        let i = self.0.trailing_zeros();
+        self.0 &= self.0 - 1;
+        NodeId::new(i as u8)
+    }
+}
@@ -0,0 +1,110 @@
+//! The message catalogue. The message types are generated from
+//! `messages.toml`; see `MESSAGES.md` for their layout.
+//!
+//! Fields are packed from bit 0 of byte 0, least significant bit first, so
+//! multi-byte fields are little-endian. Decoding ignores bytes past a message's
+//! `LEN`, so fields can be appended in later revisions without breaking older
+//! receivers.
+
+use embedded_can::Frame;
+
+use crate::id::{MessageId, MessageKind, NodeId};
+use crate::DecodeError;
+
+/// A message of the catalogue and its data layout.
+pub trait Message: Sized {
+    const KIND: MessageKind;
+    /// Data bytes sent.
+    const LEN: usize;
+    /// How often the sender broadcasts the message, if it does so periodically.
+    const CYCLE_MS: Option<u32> = None;
+
+    /// Writes the message into `data`, which is `LEN` bytes long.
+    fn encode(&self, data: &mut [u8]);
+
+    /// Reads the message from `data`, which is at least `LEN` bytes long.
+    fn decode(data: &[u8]) -> Result<Self, DecodeError>;
+
+    /// The identifier of this message when `node` sends it.
+    fn id(node: NodeId) -> MessageId {
+        MessageId::new(Self::KIND, node)
+    }
+
+    fn to_frame<F: Frame>(&self, node: NodeId) -> F {
+        let mut data = [0; 8];
+        self.encode(&mut data[..Self::LEN]);
+        F::new(Self::id(node), &data[..Self::LEN]).expect("TGIS messages fit in a classic frame")
+    }
+
+    /// A remote frame asking `node` to send this message now.
+    fn request<F: Frame>(node: NodeId) -> F {
+        F::new_remote(Self::id(node), Self::LEN).expect("TGIS messages fit in a classic frame")
+    }
+
+    /// Decodes `frame` if it carries this message, and returns the sender too.
+    fn from_frame<F: Frame>(frame: &F) -> Result<(NodeId, Self), DecodeError> {
+        let id = MessageId::try_from(frame.id())?;
+        if id.kind != Self::KIND {
+            return Err(DecodeError::WrongKind { expected: Self::KIND, found: id.kind });
+        }
+        Ok((id.node, decode_data(frame)?))
+    }
+}
+
+fn decode_data<M: Message, F: Frame>(frame: &F) -> Result<M, DecodeError> {
+    if frame.is_remote_frame() {
+        return Err(DecodeError::Remote);
+    }
+    let data = frame.data();
+    if data.len() < M::LEN {
+        return Err(DecodeError::Length { expected: M::LEN, actual: data.len() });
+    }
+    M::decode(data)
+}
+
+/// Writes the low `bits` bits of `value` into `data`, from bit `start` on.
+fn put_bits(data: &mut [u8], start: usize, bits: usize, value: u64) {
+    for i in 0..bits {
+        let (byte, mask) = ((start + i) / 8, 1 << ((start + i) % 8));
+        if value >> i & 1 != 0 {
+            data[byte] |= mask;
+        } else {
+            data[byte] &= !mask;
+        }
+    }
+}
+
+/// Reads `bits` bits from `data`, from bit `start` on.
+fn get_bits(data: &[u8], start: usize, bits: usize) -> u64 {
+    (0..bits).fold(0, |value, i| {
+        let bit = data[(start + i) / 8] >> ((start + i) % 8) & 1;
+        value | (bit as u64) << i
+    })
+}
+
+include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+
+impl Imu {
+    /// From a normalised reading in g, as `Accelerometer::accel_norm`
+    /// returns. Values beyond ±32.767 g saturate.
+    pub fn from_g(x: f32, y: f32, z: f32) -> Self {
+        Imu { x_mg: (x * 1000.0) as i16, y_mg: (y * 1000.0) as i16, z_mg: (z * 1000.0) as i16 }
+    }
+}
+
+impl AddressClaim {
+    /// The name of a board of type `board`, made unique by the RP2040's flash
+    /// unique ID. The ID is folded into the 56-bit serial by XORing its top
+    /// byte into the bottom one, so IDs that differ only in the top byte still
+    /// give different names.
+    pub fn new(board: NodeId, unique_id: [u8; 8]) -> Self {
+        let id = u64::from_be_bytes(unique_id);
+        let serial = (id & ((1 << 56) - 1)) ^ (id >> 56);
+        AddressClaim { serial, board: board.as_raw() }
+    }
+
+    /// The name as one number; the lower name wins a contested address.
+    pub fn name(&self) -> u64 {
+        (self.board as u64) << 56 | self.serial
+    }
+}
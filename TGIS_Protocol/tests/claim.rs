@@ -0,0 +1,163 @@
+use can2040::CanFrame;
+use tgis::claim::{State, CLAIM_TIMEOUT_MS};
+use tgis::messages::{Heartbeat, NodeState};
+use tgis::*;
+
+fn board(kind: NodeId, serial: u8) -> Claimer {
+    Claimer::new(AddressClaim::new(kind, [0xE6, 0x60, 0, 0, 0, 0, 0, serial]))
+}
+
+/// Delivers every frame to every other board until the bus is quiet.
+fn settle(boards: &mut [Claimer], mut pending: Vec<(usize, CanFrame)>, now_ms: u64) {
+    while let Some((from, frame)) = pending.pop() {
+        for (i, board) in boards.iter_mut().enumerate() {
+            if i != from {
+                if let Some(reply) = board.receive(&frame, now_ms) {
+                    pending.push((i, reply));
+                }
+            }
+        }
+    }
+}
+
+fn start_all(boards: &mut [Claimer]) {
+    let claims = boards.iter_mut().map(|b| b.start(0)).enumerate().collect();
+    settle(boards, claims, 0);
+    for board in boards.iter_mut() {
+        board.poll(CLAIM_TIMEOUT_MS);
+    }
+}
+
+#[test]
+fn name_orders_by_board_then_serial() {
+    let a = AddressClaim::new(NodeId::STATUS, [0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(a.serial, 1);
+    assert_eq!(a.name(), 2 << 56 | 1);
+    assert!(a.name() < AddressClaim::new(NodeId::STATUS, [0, 0, 0, 0, 0, 0, 0, 2]).name());
+    assert!(a.name() > AddressClaim::new(NodeId::POWER, [0xFF; 8]).name());
+    let frame: CanFrame = a.to_frame(NodeId::STATUS);
+    assert_eq!(AddressClaim::from_frame(&frame), Ok((NodeId::STATUS, a)));
+}
+
+#[test]
+fn whole_unique_id_goes_into_the_name() {
+    let a = AddressClaim::new(NodeId::STATUS, [0xFF, 0, 0, 0, 0, 0, 0, 1]);
+    let b = AddressClaim::new(NodeId::STATUS, [0x01, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!((a.serial, b.serial), (0xFE, 0));
+    assert_ne!(a.name(), b.name());
+    let high = AddressClaim::new(NodeId::STATUS, [0x12, 0x34, 0, 0, 0, 0, 0x56, 0x78]);
+    assert_eq!(high.serial, 0x34_0000_0000_566A);
+}
+
+#[test]
+fn lone_board_gets_its_preferred_address() {
+    let mut claimer = board(NodeId::STATUS, 1);
+    let claim: CanFrame = claimer.start(100);
+    assert_eq!(AddressClaim::from_frame(&claim).unwrap().0, NodeId::STATUS);
+    assert_eq!(claimer.address(), None);
+    assert_eq!(claimer.poll(100 + CLAIM_TIMEOUT_MS - 1), None);
+    assert_eq!(claimer.poll(100 + CLAIM_TIMEOUT_MS), Some(NodeId::STATUS));
+    assert_eq!(claimer.address(), Some(NodeId::STATUS));
+}
+
+#[test]
+fn identical_boards_resolve_the_conflict() {
+    let mut boards = [board(NodeId::STATUS, 9), board(NodeId::STATUS, 3), board(NodeId::POWER, 1)];
+    start_all(&mut boards);
+    // The lowest serial keeps the address, the other moves to the next free one.
+    assert_eq!(boards[1].address(), Some(NodeId::STATUS));
+    assert_eq!(boards[0].address(), Some(NodeId::USB_CAN));
+    assert_eq!(boards[2].address(), Some(NodeId::POWER));
+}
+
+#[test]
+fn skips_addresses_in_use() {
+    let mut claimer = board(NodeId::STATUS, 9);
+    let beat = Heartbeat { state: NodeState::Operational, sequence: 0, uptime_s: 5 };
+    assert!(claimer.receive::<CanFrame>(&beat.to_frame(NodeId::USB_CAN), 0).is_none());
+    let _: CanFrame = claimer.start(0);
+
+    let holder = AddressClaim::new(NodeId::STATUS, [0; 8]);
+    let reply: CanFrame = claimer.receive(&holder.to_frame(NodeId::STATUS), 10).unwrap();
+    assert_eq!(AddressClaim::from_frame(&reply), Ok((NodeId::TERMINATION, *claimer.name())));
+    assert_eq!(claimer.state(), State::Claiming { address: NodeId::TERMINATION, since_ms: 10 });
+}
+
+#[test]
+fn moves_off_an_address_used_without_a_claim() {
+    let beat = Heartbeat { state: NodeState::Operational, sequence: 0, uptime_s: 5 };
+
+    // Heard before starting: the preferred address is never claimed.
+    let mut claimer = board(NodeId::STATUS, 1);
+    assert!(claimer.receive::<CanFrame>(&beat.to_frame(NodeId::STATUS), 0).is_none());
+    let claim: CanFrame = claimer.start(0);
+    assert_eq!(AddressClaim::from_frame(&claim).unwrap().0, NodeId::USB_CAN);
+
+    // Heard while claiming, and once claimed.
+    let mut claimer = board(NodeId::STATUS, 1);
+    let _: CanFrame = claimer.start(0);
+    let reply: CanFrame = claimer.receive(&beat.to_frame(NodeId::STATUS), 10).unwrap();
+    assert_eq!(AddressClaim::from_frame(&reply), Ok((NodeId::USB_CAN, *claimer.name())));
+    claimer.poll(10 + CLAIM_TIMEOUT_MS);
+    let reply: CanFrame = claimer.receive(&beat.to_frame(NodeId::USB_CAN), 500).unwrap();
+    assert_eq!(AddressClaim::from_frame(&reply), Ok((NodeId::TERMINATION, *claimer.name())));
+    assert_eq!(claimer.state(), State::Claiming { address: NodeId::TERMINATION, since_ms: 500 });
+
+    // A request for the node we are is not traffic from it.
+    let request = Heartbeat::request::<CanFrame>(NodeId::TERMINATION);
+    assert!(claimer.receive(&request, 600).is_none());
+    assert_eq!(claimer.poll(500 + CLAIM_TIMEOUT_MS), Some(NodeId::TERMINATION));
+}
+
+#[test]
+fn board_without_a_preferred_address_tries_all_of_them() {
+    let mut claimer = board(NodeId::UNASSIGNED, 1);
+    let beat = Heartbeat { state: NodeState::Operational, sequence: 0, uptime_s: 5 };
+    for address in 1..NodeId::MAX.as_raw() {
+        let node = NodeId::new(address).unwrap();
+        assert!(claimer.receive::<CanFrame>(&beat.to_frame(node), 0).is_none());
+    }
+    let claim: CanFrame = claimer.start(0);
+    assert_eq!(AddressClaim::from_frame(&claim).unwrap().0, NodeId::MAX);
+    assert_eq!(claimer.poll(CLAIM_TIMEOUT_MS), Some(NodeId::MAX));
+}
+
+#[test]
+fn defends_a_claimed_address() {
+    let mut claimer = board(NodeId::STATUS, 1);
+    let _: CanFrame = claimer.start(0);
+    claimer.poll(CLAIM_TIMEOUT_MS);
+
+    let newcomer = AddressClaim::new(NodeId::STATUS, [0xFF; 8]);
+    let reply: CanFrame = claimer.receive(&newcomer.to_frame(NodeId::STATUS), 1_000).unwrap();
+    assert_eq!(AddressClaim::from_frame(&reply), Ok((NodeId::STATUS, *claimer.name())));
+    assert_eq!(claimer.address(), Some(NodeId::STATUS));
+
+    // Our own claim looped back is not a conflict.
+    assert!(claimer.receive(&reply, 1_000).is_none());
+}
+
+#[test]
+fn answers_enumeration_requests() {
+    let mut claimer = board(NodeId::POWER, 1);
+    let request = AddressClaim::request::<CanFrame>(NodeId::UNASSIGNED);
+    assert!(claimer.receive(&request, 0).is_none(), "not claiming yet");
+
+    let _: CanFrame = claimer.start(0);
+    let reply = claimer.receive(&request, 1).unwrap();
+    assert_eq!(AddressClaim::from_frame(&reply), Ok((NodeId::POWER, *claimer.name())));
+    assert!(claimer.receive(&AddressClaim::request::<CanFrame>(NodeId::STATUS), 1).is_none());
+}
+
+#[test]
+fn gives_up_when_every_address_is_taken() {
+    let mut boards: Vec<_> = (0..16).map(|serial| board(NodeId::STATUS, serial)).collect();
+    start_all(&mut boards);
+    let addresses: Vec<_> = boards.iter().filter_map(Claimer::address).collect();
+    assert_eq!(addresses.len(), 15);
+    // The highest name loses every address.
+    let last = boards.iter_mut().max_by_key(|b| b.name().name()).unwrap();
+    assert_eq!(last.state(), State::CannotClaim);
+    let reply = last.receive(&AddressClaim::request::<CanFrame>(NodeId::UNASSIGNED), 0);
+    assert_eq!(AddressClaim::from_frame(&reply.unwrap()).unwrap().0, NodeId::UNASSIGNED);
+}
@@ -0,0 +1,28 @@
+//! The checked-in `tgis.dbc` and `MESSAGES.md` must match what `messages.toml`
+//! generates. Run with `TGIS_BLESS=1` to update them.
+
+use std::path::Path;
+
+fn check(name: &str, generated: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(name);
+    if std::env::var_os("TGIS_BLESS").is_some() {
+        std::fs::write(&path, generated).unwrap();
+        return;
+    }
+    let checked_in = std::fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        checked_in == generated,
+        "{} is out of date with messages.toml, update it with `TGIS_BLESS=1 cargo test`",
+        name
+    );
+}
+
+#[test]
+fn dbc_is_up_to_date() {
+    check("tgis.dbc", include_str!(concat!(env!("OUT_DIR"), "/tgis.dbc")));
+}
+
+#[test]
+fn markdown_is_up_to_date() {
+    check("MESSAGES.md", include_str!(concat!(env!("OUT_DIR"), "/MESSAGES.md")));
+}
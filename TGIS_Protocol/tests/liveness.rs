@@ -0,0 +1,48 @@
+use tgis::liveness::{Event, PERIOD_MS};
+use tgis::messages::{Heartbeat, NodeState};
+use tgis::{Liveness, NodeId};
+
+fn beat(uptime_s: u32) -> Heartbeat {
+    Heartbeat { state: NodeState::Operational, sequence: uptime_s as u8, uptime_s }
+}
+
+#[test]
+fn node_is_lost_after_missed_periods() {
+    let mut liveness = Liveness::new(3);
+    assert_eq!(liveness.heartbeat(NodeId::POWER, &beat(1), 0), Some(Event::Joined));
+    assert_eq!(liveness.heartbeat(NodeId::POWER, &beat(2), PERIOD_MS), None);
+
+    // Three missed heartbeats are tolerated, the fourth is not.
+    assert_eq!(liveness.check(4 * PERIOD_MS).count(), 0);
+    assert_eq!(liveness.check(4 * PERIOD_MS + 1).collect::<Vec<_>>(), [NodeId::POWER]);
+    assert!(liveness.peer(NodeId::POWER).unwrap().lost);
+    // Reported once.
+    assert_eq!(liveness.check(10 * PERIOD_MS).count(), 0);
+
+    assert_eq!(liveness.heartbeat(NodeId::POWER, &beat(12), 11 * PERIOD_MS), Some(Event::Returned));
+    assert_eq!(liveness.alive().count(), 1);
+}
+
+#[test]
+fn tracks_nodes_independently() {
+    let mut liveness = Liveness::new(2);
+    liveness.heartbeat(NodeId::POWER, &beat(5), 0);
+    liveness.heartbeat(NodeId::USB_CAN, &beat(5), 0);
+    liveness.heartbeat(NodeId::TERMINATION, &beat(5), 0);
+    liveness.heartbeat(NodeId::USB_CAN, &beat(7), 2 * PERIOD_MS);
+
+    let lost: Vec<_> = liveness.check(3 * PERIOD_MS).collect();
+    assert_eq!(lost, [NodeId::POWER, NodeId::TERMINATION]);
+    let alive: Vec<_> = liveness.alive().map(|(node, _)| node).collect();
+    assert_eq!(alive, [NodeId::USB_CAN]);
+    assert_eq!(liveness.lost().count(), 2);
+    assert_eq!(liveness.peer(NodeId::STATUS), None);
+}
+
+#[test]
+fn notices_restarts() {
+    let mut liveness = Liveness::new(3);
+    liveness.heartbeat(NodeId::STATUS, &beat(600), 0);
+    assert_eq!(liveness.heartbeat(NodeId::STATUS, &beat(0), 500), Some(Event::Restarted));
+    assert_eq!(liveness.peer(NodeId::STATUS).unwrap().uptime_s, 0);
+}
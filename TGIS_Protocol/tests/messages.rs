@@ -0,0 +1,108 @@
+use can2040::CanFrame;
+use embedded_can::{ExtendedId, Frame, StandardId};
+use tgis::*;
+
+fn frame(raw_id: u16, data: &[u8]) -> CanFrame {
+    CanFrame::new(StandardId::new(raw_id).unwrap(), data).unwrap()
+}
+
+fn round_trip<M: Message + PartialEq + core::fmt::Debug>(message: M) {
+    let frame: CanFrame = message.to_frame(NodeId::STATUS);
+    assert_eq!(frame.dlc(), M::LEN);
+    assert_eq!(M::from_frame(&frame), Ok((NodeId::STATUS, message)));
+}
+
+#[test]
+fn messages_round_trip() {
+    round_trip(Leak { detected: true });
+    round_trip(Leak { detected: false });
+    round_trip(Power {
+        rail_3v3_mv: 3_312,
+        rail_3v3_ma: 1_250,
+        rail_5v_mv: 5_020,
+        rail_5v_ma: 65_535,
+    });
+    round_trip(Imu { x_mg: -1, y_mg: i16::MIN, z_mg: 1_000 });
+    round_trip(Heartbeat { state: NodeState::Degraded, sequence: 255, uptime_s: u32::MAX });
+}
+
+#[test]
+fn wire_format_is_stable() {
+    let leak: CanFrame = Leak { detected: true }.to_frame(NodeId::STATUS);
+    assert_eq!((leak.id(), leak.data()), (frame(0x012, &[]).id(), &[1][..]));
+
+    let imu: CanFrame = Imu { x_mg: 1, y_mg: -2, z_mg: 0x1234 }.to_frame(NodeId::STATUS);
+    assert_eq!(imu.id(), frame(0x332, &[]).id());
+    assert_eq!(imu.data(), [0x01, 0x00, 0xFE, 0xFF, 0x34, 0x12]);
+
+    let beat: CanFrame =
+        Heartbeat { state: NodeState::Operational, sequence: 7, uptime_s: 0x010203 }
+            .to_frame(NodeId::POWER);
+    assert_eq!(beat.id(), frame(0x441, &[]).id());
+    assert_eq!(beat.data(), [1, 7, 0x03, 0x02, 0x01, 0x00]);
+}
+
+#[test]
+fn periodic_messages_state_their_cycle() {
+    assert_eq!(Heartbeat::CYCLE_MS, Some(1_000));
+    assert_eq!(Leak::CYCLE_MS, Some(500));
+    assert_eq!(Imu::CYCLE_MS, None);
+}
+
+#[test]
+fn priority_classes_order_arbitration() {
+    let mut ids: Vec<_> = MessageKind::ALL
+        .iter()
+        .flat_map(|&kind| {
+            (0..=15).map(move |node| MessageId::new(kind, NodeId::new(node).unwrap()))
+        })
+        .collect();
+    ids.sort_by_key(|id| id.as_raw());
+    assert!(ids.windows(2).all(|w| w[0].priority() <= w[1].priority()));
+    for id in ids {
+        assert_eq!(MessageId::from_raw(id.as_raw()), Ok(id));
+    }
+}
+
+#[test]
+fn any_message_dispatches_on_kind() {
+    let beat = Heartbeat { state: NodeState::Booting, sequence: 0, uptime_s: 0 };
+    let frame: CanFrame = beat.to_frame(NodeId::USB_CAN);
+    assert_eq!(AnyMessage::from_frame(&frame), Ok((NodeId::USB_CAN, AnyMessage::Heartbeat(beat))));
+    let again: CanFrame = AnyMessage::Heartbeat(beat).to_frame(NodeId::USB_CAN);
+    assert_eq!(again.id(), frame.id());
+}
+
+#[test]
+fn rejects_what_is_not_a_tgis_message() {
+    let extended = CanFrame::new(ExtendedId::new(0x12).unwrap(), &[1]).unwrap();
+    assert_eq!(AnyMessage::from_frame(&extended), Err(DecodeError::Extended));
+    // The old demo leak frame on COB-ID 5.
+    assert_eq!(
+        AnyMessage::from_frame(&frame(0x005, &[1, 2, 3, 1])),
+        Err(DecodeError::UnknownId(5))
+    );
+    // Leak kind in the heartbeat class.
+    assert_eq!(Leak::from_frame(&frame(0x412, &[1])), Err(DecodeError::UnknownId(0x412)));
+    assert_eq!(
+        Leak::from_frame(&frame(0x442, &[1, 0])),
+        Err(DecodeError::WrongKind { expected: MessageKind::Leak, found: MessageKind::Heartbeat })
+    );
+    assert_eq!(
+        Imu::from_frame(&frame(0x332, &[0; 4])),
+        Err(DecodeError::Length { expected: 6, actual: 4 })
+    );
+    assert_eq!(
+        Leak::from_frame(&Leak::request::<CanFrame>(NodeId::STATUS)),
+        Err(DecodeError::Remote)
+    );
+    assert_eq!(
+        Heartbeat::from_frame(&frame(0x442, &[9, 0, 0, 0, 0, 0])),
+        Err(DecodeError::InvalidValue { offset: 0 })
+    );
+    // Bytes appended by a later revision are ignored.
+    assert_eq!(
+        Leak::from_frame(&frame(0x012, &[1, 0xAA])),
+        Ok((NodeId::STATUS, Leak { detected: true }))
+    );
+}